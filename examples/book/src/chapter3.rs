@@ -0,0 +1,9 @@
+// mdbook-rust:title "Chapter 3: \"Metadata\""
+// mdbook-rust:tags "metadata, directives"
+
+pub fn body() {
+    // Directives in the comments at the start of a chapter set its metadata,
+    // like its title. They're just comments, so the chapter still compiles.
+    let compiles = true;
+    assert!(compiles);
+}
@@ -1 +1,2 @@
 pub mod chapter1;
+pub mod chapter3;
@@ -0,0 +1,82 @@
+//! Render a large, generated `body` function, and report how long it takes and
+//! how many allocations it makes. The output is checked against the Markdown
+//! the generated body has always rendered to, so changes that cut allocations
+//! can't change the output.
+//!
+//! Run with `cargo bench --bench large_body`.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use mdbook_rust::write_module;
+
+const SECTIONS: usize = 5000;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let mut source = String::from("fn body() {\n");
+    let mut expected = String::new();
+
+    for section in 0..SECTIONS {
+        writeln!(
+            source,
+            "    // ## Section {section}\n    //\n    // Some text about section {section}.\n    \
+             let x{section} = {section};\n    let y{section} = x{section} + 1;\n"
+        )
+        .unwrap();
+
+        if section > 0 {
+            expected.push_str("\n\n");
+        }
+
+        write!(
+            expected,
+            "## Section {section}\n\nSome text about section {section}.\n\n```rust,ignore\n\
+             let x{section} = {section};\nlet y{section} = x{section} + 1;\n```"
+        )
+        .unwrap();
+    }
+
+    source.push_str("}\n");
+    expected.push('\n');
+
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let markdown = write_module(&source).unwrap().unwrap();
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    assert!(markdown == expected, "The rendered Markdown has changed");
+
+    println!(
+        "Rendered {SECTIONS} sections ({} bytes) in {elapsed:?}, with {allocations} allocations \
+         ({} per section)",
+        markdown.len(),
+        allocations / SECTIONS
+    );
+}
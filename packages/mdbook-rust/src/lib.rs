@@ -1,246 +1,2441 @@
-use std::{cmp::min, collections::VecDeque, fmt::Display};
+use std::{
+    borrow::Cow,
+    cmp::{max, min},
+    collections::VecDeque,
+    fmt, io, iter, mem,
+    ops::Range,
+    path::PathBuf,
+};
 
-use anyhow::{bail, Result};
-use itertools::Itertools;
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::{Either, Itertools};
+pub use preprocessor::{
+    check_version, diagnose, unknown_config_keys, write_book, write_chapter, ChapterDiagnosis,
+    ChapterSummary, RustPreprocessor,
+};
 use ra_ap_syntax::{
-    ast::{self, HasModuleItem, HasName, Item},
-    AstNode, AstToken, NodeOrToken, SourceFile, SyntaxKind, SyntaxNode, SyntaxToken,
+    ast::{self, HasAttrs, HasModuleItem, HasName, HasVisibility, IsString, Item},
+    AstNode, AstToken, Direction, NodeOrToken, SourceFile, SyntaxElement, SyntaxKind, SyntaxNode,
+    SyntaxToken, TextRange, TextSize,
 };
 
+mod preprocessor;
+
+/// Options controlling how a module is rendered to Markdown.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RenderOptions {
+    /// The info string used for generated code fences, for example
+    /// `rust,no_run`.
+    pub code_attributes: String,
+    /// Make code blocks editable in the rendered book. This removes any
+    /// `ignore` attribute, so readers can run the code.
+    pub editable: bool,
+    /// Wrap the code in each code block in a hidden `fn main`.
+    pub wrap_main: bool,
+    /// Only add `ignore` to code blocks that use `self`, `Self` or `super`
+    /// from the entry function's context, so they can't be compiled on their
+    /// own. This is a best-effort heuristic, so other code blocks might still
+    /// fail to compile.
+    pub auto_ignore: bool,
+    /// Wrap the code in each code block in hidden context, so it compiles.
+    pub context: CodeContext,
+    /// Show the chapter's file name at the top of the chapter. This only
+    /// applies to [`write_chapter`], as it needs the chapter's path.
+    pub show_source_name: bool,
+    /// A URL to link to each chapter's source file from, like
+    /// `https://github.com/user/repo/blob/main/src`. The link is added to the
+    /// end of the chapter. This only applies to [`write_chapter`].
+    pub source_link_base: Option<String>,
+    /// What to do with a `# Heading` at the start of the chapter, which would
+    /// repeat the chapter's name. This only applies to [`write_chapter`].
+    pub first_heading: FirstHeading,
+    /// Show any `#[test]` functions at the end of the chapter, in a collapsed
+    /// section.
+    pub include_tests: bool,
+    /// The active `cfg` options, like `unix` or `feature = "serde"`. If this is
+    /// set, statements with `#[cfg(...)]` attributes that don't match are left
+    /// out, and the attributes are removed from the ones that do.
+    pub cfg: Option<Vec<String>>,
+    /// A directory to cache rendered chapters in, so unchanged chapters
+    /// aren't rendered again. This only applies to [`write_chapter`].
+    pub cache_dir: Option<PathBuf>,
+    /// The book's source directory. Paths in `// mdbook-rust:include`
+    /// directives are relative to the chapter, which is relative to this. This
+    /// only applies to [`write_chapter`].
+    pub source_dir: Option<PathBuf>,
+    /// How to render `///` doc comments.
+    pub doc_comments: DocComments,
+    /// How much space to remove after the `//` of comments rendered as
+    /// Markdown.
+    pub comment_gutter: CommentGutter,
+    /// Move headings in comments, and inline module headings, down this many
+    /// levels, up to level 6. This is useful when chapters are embedded in a
+    /// larger document.
+    pub heading_offset: usize,
+    /// What to do with chapters that fail to parse.
+    pub on_parse_error: OnParseError,
+    /// How to show code blocks.
+    pub code: CodeDisplay,
+    /// Put an HTML comment with the source line numbers before each code
+    /// block. This is useful for debugging.
+    pub line_numbers: bool,
+    /// The file extensions of Rust source chapters, without the leading `.`.
+    /// Other chapters are left as they are. This only applies to
+    /// [`write_chapter`] and [`write_book`].
+    pub extensions: Vec<String>,
+    /// Remove the common indentation from code. If this is `false`, code is
+    /// indented as it is in the source.
+    pub dedent: bool,
+    /// How to find the indentation to remove, if `dedent` is set.
+    pub dedent_strategy: DedentStrategy,
+    /// Replace tabs in the indentation of code with spaces, up to the next
+    /// multiple of this many columns. Tabs in string literals are left alone.
+    /// This is also the width of a tab when removing indentation, which is 4
+    /// columns if it isn't set.
+    pub tab_width: Option<usize>,
+    /// Copy code exactly as it is in the source, apart from the dedent, rather
+    /// than rebuilding it from tokens. This keeps the whitespace between
+    /// statements, rather than just the line breaks, and doesn't expand tabs.
+    pub verbatim_code: bool,
+    /// The name of the entry function. Functions with this name, or this name
+    /// followed by `_` and a suffix, are rendered in the order they're
+    /// declared.
+    pub entry_function: String,
+    /// Render the whole file as the chapter, rather than entry functions.
+    /// Top level comments are written as Markdown, and items as code.
+    pub whole_file: bool,
+    /// Show the entry function's signature, including its return type, in
+    /// inline code at the top of the chapter.
+    pub show_signature: bool,
+    /// Fail, rather than warn, on mistakes like an entry function with
+    /// parameters.
+    pub deny_warnings: bool,
+    /// Put YAML front matter, with the chapter's title and any tags, at the
+    /// top of the chapter. This only applies to [`write_chapter`].
+    pub front_matter: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            code_attributes: "rust,ignore".to_string(),
+            editable: false,
+            wrap_main: false,
+            auto_ignore: false,
+            context: CodeContext::default(),
+            show_source_name: false,
+            source_link_base: None,
+            first_heading: FirstHeading::default(),
+            include_tests: false,
+            cfg: None,
+            cache_dir: None,
+            source_dir: None,
+            doc_comments: DocComments::default(),
+            comment_gutter: CommentGutter::default(),
+            heading_offset: 0,
+            on_parse_error: OnParseError::default(),
+            code: CodeDisplay::default(),
+            line_numbers: false,
+            extensions: vec!["rs".to_string()],
+            dedent: true,
+            dedent_strategy: DedentStrategy::default(),
+            tab_width: None,
+            verbatim_code: false,
+            entry_function: "body".to_string(),
+            whole_file: false,
+            show_signature: false,
+            deny_warnings: false,
+            front_matter: false,
+        }
+    }
+}
+
+impl RenderOptions {
+    fn fence_info(&self) -> String {
+        if self.editable || self.auto_ignore {
+            let attributes = self
+                .code_attributes
+                .split(',')
+                .filter(|attribute| *attribute != "ignore");
+
+            if self.editable {
+                attributes.chain(iter::once("editable")).join(",")
+            } else {
+                attributes.join(",")
+            }
+        } else {
+            self.code_attributes.clone()
+        }
+    }
+}
+
+/// How to render `///` doc comments.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DocComments {
+    /// Leave them in the code.
+    #[default]
+    Code,
+    /// Render them as Markdown, like other comments. Intra-doc links with
+    /// inline code, like [`Vec`], are written as inline code, as mdBook can't
+    /// resolve them.
+    Markdown,
+}
+
+/// What to do with a `# Heading` at the start of a chapter.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum FirstHeading {
+    /// Leave it in the chapter.
+    #[default]
+    Keep,
+    /// Use it as the chapter's name, and remove it from the chapter.
+    Use,
+    /// Remove it from the chapter.
+    Strip,
+}
+
+/// How much space to remove after the `//` of each line comment.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CommentGutter {
+    /// Remove the space that all the lines in a run of `//` comments have in
+    /// common, so indentation relative to that is kept. Lines with no space
+    /// after the `//` are ignored.
+    #[default]
+    Auto,
+    /// Remove up to this many spaces from each line.
+    Spaces(usize),
+}
+
+/// What to do with a chapter that fails to parse.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OnParseError {
+    /// Fail the whole book.
+    #[default]
+    Fail,
+    /// Leave the chapter's content as it is, with a warning at the top. Other
+    /// errors, like a denied warning, still fail the book.
+    Skip,
+}
+
+/// The hidden context that code blocks are wrapped in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CodeContext {
+    /// Don't add any context.
+    #[default]
+    None,
+    /// Wrap the code in the entry function's signature, so its generic
+    /// parameters are in scope. Entry functions are never methods, so this
+    /// doesn't bring `self` into scope.
+    Function,
+}
+
+/// How to show code blocks.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CodeDisplay {
+    /// Show them as they are.
+    #[default]
+    Show,
+    /// Leave them out, so only the Markdown is rendered.
+    Hide,
+    /// Put each one in a collapsed `<details>` section.
+    Collapse,
+}
+
+/// How to find the indentation to remove from code.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DedentStrategy {
+    /// Remove the smallest indentation of any line, so no code is cut off.
+    #[default]
+    Min,
+    /// Remove the smallest indentation of the lines where statements start,
+    /// ignoring statements that start with a label or attribute, so an
+    /// outdented label doesn't stop the rest being dedented. Lines with less
+    /// indentation are left as they are.
+    Common,
+}
+
+/// A chapter rendered from a Rust module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RenderedChapter {
+    /// The Markdown for the whole chapter.
+    pub markdown: String,
+    /// Each code block in `markdown`, in order.
+    pub code_blocks: Vec<CodeBlock>,
+    /// The text of each heading in `markdown`, in order.
+    pub headings: Vec<String>,
+    /// Any warnings, starting with the line number they apply to.
+    pub warnings: Vec<String>,
+}
+
+/// A fenced code block in a [`RenderedChapter`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CodeBlock {
+    /// The Rust code, as it appears inside the fence. For a `diff`, this is
+    /// the code without the `+`, `-` and context prefixes.
+    pub code: String,
+    /// The byte range of the code in the source text, after normalizing line
+    /// endings to `\n`.
+    pub source_range: Range<usize>,
+}
+
+impl RenderedChapter {
+    fn prepend(&mut self, markdown: &str) {
+        self.markdown = if self.markdown.is_empty() {
+            format!("{markdown}\n")
+        } else {
+            format!("{markdown}\n\n{}", self.markdown.trim_start_matches('\n'))
+        };
+        let mut headings = headings(markdown);
+        headings.append(&mut self.headings);
+        self.headings = headings;
+    }
+
+    fn append(&mut self, other: Self) {
+        if !self.markdown.is_empty() && !other.markdown.is_empty() {
+            self.markdown.push('\n');
+        }
+
+        self.markdown.push_str(&other.markdown);
+        self.code_blocks.extend(other.code_blocks);
+        self.headings.extend(other.headings);
+        self.warnings.extend(other.warnings);
+    }
+}
+
+/// Render a module's entry functions as Markdown, using the default options.
+///
+/// Returns `None` if there are no entry functions.
 pub fn write_module(source_text: &str) -> Result<Option<String>> {
+    write_module_with(source_text, &RenderOptions::default())
+}
+
+/// Render a module's entry functions as Markdown.
+///
+/// Returns `None` if there are no entry functions.
+pub fn write_module_with(source_text: &str, options: &RenderOptions) -> Result<Option<String>> {
+    let mut markdown = Vec::new();
+
+    if render_module(source_text, &mut markdown, options)? {
+        Ok(Some(String::from_utf8(markdown)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Render a module's entry functions as Markdown, and write it to `out`.
+///
+/// Returns `false`, without writing anything, if there are no entry functions.
+pub fn render_module<W: io::Write>(
+    source_text: &str,
+    out: &mut W,
+    options: &RenderOptions,
+) -> Result<bool> {
+    match write_module_structured_with(source_text, options)? {
+        Some(chapter) => {
+            out.write_all(chapter.markdown.as_bytes())?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Render a module's entry functions, along with the code blocks and headings
+/// in them, using the default options.
+///
+/// Returns `None` if there are no entry functions.
+pub fn write_module_structured(source_text: &str) -> Result<Option<RenderedChapter>> {
+    write_module_structured_with(source_text, &RenderOptions::default())
+}
+
+/// Render a module's entry functions, along with the code blocks and headings
+/// in them.
+///
+/// Returns `None` if there are no entry functions.
+pub fn write_module_structured_with(
+    source_text: &str,
+    options: &RenderOptions,
+) -> Result<Option<RenderedChapter>> {
+    let mut warnings = Vec::new();
+    let mut chapter = write_source(&parse_module(source_text)?, options, &mut warnings)?;
+
+    if let Some(chapter) = &mut chapter {
+        chapter.warnings = warnings;
+    }
+
+    Ok(chapter)
+}
+
+/// Read a chapter's metadata and render it, from a single parse. Drafts are
+/// rendered as an empty string. The Markdown is `None` if there are no entry
+/// functions.
+fn write_chapter_source(
+    source_text: &str,
+    options: &RenderOptions,
+) -> Result<(ChapterMetadata, Option<String>)> {
     let source = parse_module(source_text)?;
+    let mut metadata = chapter_metadata(&source)?;
+
+    if metadata.draft {
+        return Ok((metadata, Some(String::new())));
+    }
+
+    let chapter = write_source(&source, options, &mut metadata.warnings)?;
+
+    Ok((metadata, chapter.map(|chapter| chapter.markdown)))
+}
+
+/// Render a parsed module, adding any warnings about its entry functions to
+/// `warnings`.
+fn write_source(
+    source: &SourceFile,
+    options: &RenderOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Option<RenderedChapter>> {
+    let mut chapter = if options.whole_file {
+        Some(write_file(source, options))
+    } else {
+        write_items(source.items(), 1, options, warnings)?
+    };
+
+    if let Some(chapter) = &mut chapter {
+        if options.include_tests {
+            if let Some(tests) = write_tests(source, options) {
+                chapter.append(tests);
+            }
+        }
+
+        if let Some(inner_docs) = inner_doc_comments(source, options) {
+            chapter.prepend(&inner_docs);
+        }
+
+        chapter.markdown = collapse_blank_lines(&chapter.markdown);
+    }
+
+    Ok(chapter)
+}
+
+/// Render the entry functions in `items`, and any public inline modules.
+///
+/// `heading_level` is the level of the heading for the module containing
+/// `items`. Warnings about entry functions are added to `warnings`, even if
+/// the function can't be rendered.
+fn write_items(
+    items: impl Iterator<Item = Item>,
+    heading_level: usize,
+    options: &RenderOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Option<RenderedChapter>> {
+    let mut chapter: Option<RenderedChapter> = None;
+
+    for item in items {
+        let item_chapter = match item {
+            Item::Fn(function)
+                if is_entry_function(&function, &options.entry_function)
+                    && !is_skipped(&function) =>
+            {
+                warnings.extend(check_entry_function(&function, options)?);
+                write_function(function, options, warnings)?
+            }
+            Item::Module(module) if module.visibility().is_some() => {
+                write_inline_module(module, heading_level + 1, options, warnings)?
+            }
+            _ => None,
+        };
+
+        if let Some(item_chapter) = item_chapter {
+            match &mut chapter {
+                Some(chapter) => chapter.append(item_chapter),
+                None => chapter = Some(item_chapter),
+            }
+        }
+    }
+
+    Ok(chapter)
+}
+
+/// Render every top level item and comment in `source`, for
+/// [`RenderOptions::whole_file`].
+///
+/// Inner doc comments and tests are left out, as they're rendered separately.
+fn write_file(source: &SourceFile, options: &RenderOptions) -> RenderedChapter {
+    let source_text = source.syntax().to_string();
+    let line_numbers_text = if options.line_numbers {
+        source_text.as_str()
+    } else {
+        ""
+    };
+    let mut writer = BodyWriter::new(0, fence(&source_text), line_numbers_text, options);
+
+    if options.wrap_main {
+        writer.wrappers.push("fn main() {".to_string());
+    }
+
+    writer.output.reserve(source_text.len());
+
+    let children = source
+        .syntax()
+        .children_with_tokens()
+        .filter(|child| match child {
+            NodeOrToken::Node(node) => {
+                ast::Fn::cast(node.clone()).map_or(true, |function| !is_test(&function))
+            }
+            NodeOrToken::Token(token) => {
+                ast::Comment::cast(token.clone()).map_or(true, |comment| !comment.is_inner())
+            }
+        })
+        .skip_while(|child| child.kind() == SyntaxKind::WHITESPACE);
+
+    for child in children {
+        writer.write_node_or_token(child);
+    }
+
+    writer.finish()
+}
+
+/// Render an inline module, like `pub mod name { ... }`, under a heading with
+/// the module's name.
+fn write_inline_module(
+    module: ast::Module,
+    heading_level: usize,
+    options: &RenderOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Option<RenderedChapter>> {
+    if let (Some(name), Some(items)) = (module.name(), module.item_list()) {
+        let mut chapter = write_items(items.items(), heading_level, options, warnings)?;
+
+        if let Some(chapter) = &mut chapter {
+            let level = min(heading_level + options.heading_offset, 6);
+            chapter.prepend(&format!("{} {name}", "#".repeat(level)));
+        }
+
+        Ok(chapter)
+    } else {
+        Ok(None)
+    }
+}
+
+/// The file's inner doc comments (`//!`), rendered as Markdown.
+fn inner_doc_comments(source: &SourceFile, options: &RenderOptions) -> Option<String> {
+    let mut comments = source
+        .syntax()
+        .children_with_tokens()
+        .filter_map(|child| ast::Comment::cast(child.into_token()?))
+        .filter(|comment| comment.is_inner())
+        .peekable();
+    comments.peek()?;
+    let mut output = String::new();
+    let mut run = None;
+
+    for (index, comment) in comments.enumerate() {
+        if index > 0 {
+            output.push('\n');
+        }
+
+        let gutter = comment_run(&mut run, &comment, options).gutter;
+        write_comment(&mut output, comment, 0, gutter, options.tab_width);
+    }
+
+    if options.heading_offset > 0 || options.doc_comments == DocComments::Markdown {
+        let mut fences = Fences::default();
+        let markdown = output;
+        output = String::with_capacity(markdown.len());
+
+        for (index, line) in markdown.split('\n').enumerate() {
+            if index > 0 {
+                output.push('\n');
+            }
+
+            if fences.is_code(line) {
+                output.push_str(line);
+            } else {
+                let line = doc_prose(line, true, options);
+                write_prose_line(&mut output, &line, options.heading_offset);
+            }
+        }
+    }
+
+    Some(output)
+}
+
+/// Write the `#[test]` functions in `source` to a collapsed `<details>`
+/// section.
+fn write_tests(source: &SourceFile, options: &RenderOptions) -> Option<RenderedChapter> {
+    let mut markdown = String::from("<details>\n<summary>Tests</summary>\n");
+    let mut code_blocks = Vec::new();
 
     for item in source.items() {
         if let Item::Fn(function) = item {
-            if is_named(&function, "body") {
-                if let Some(new_content) = write_function(function)? {
-                    return Ok(Some(new_content));
+            if is_test(&function) {
+                // Leave out any comments attached to the function.
+                let children = function
+                    .syntax()
+                    .children_with_tokens()
+                    .skip_while(|child| {
+                        child.kind() == SyntaxKind::COMMENT
+                            || child.kind() == SyntaxKind::WHITESPACE
+                    })
+                    .collect::<Vec<_>>();
+                let start = children.first()?.text_range().start();
+                let end = function.syntax().text_range().end();
+                let code = children
+                    .iter()
+                    .map(|child| child.to_string())
+                    .collect::<String>();
+
+                let fence = fence(&code);
+                markdown.push('\n');
+                markdown.push_str(&fence);
+                markdown.push_str(&options.fence_info());
+                markdown.push('\n');
+                markdown.push_str(&code);
+                markdown.push('\n');
+                markdown.push_str(&fence);
+                markdown.push('\n');
+                code_blocks.push(CodeBlock {
+                    code,
+                    source_range: usize::from(start)..usize::from(end),
+                });
+            }
+        }
+    }
+
+    if code_blocks.is_empty() {
+        return None;
+    }
+
+    markdown.push_str("\n</details>\n");
+
+    Some(RenderedChapter {
+        markdown,
+        code_blocks,
+        headings: Vec::new(),
+        warnings: Vec::new(),
+    })
+}
+
+fn is_test(function: &ast::Fn) -> bool {
+    function.attrs().any(|attr| {
+        attr.path()
+            .is_some_and(|path| path.syntax().text() == "test")
+    })
+}
+
+fn write_function(
+    function: ast::Fn,
+    options: &RenderOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Option<RenderedChapter>> {
+    if let Some(stmt_list) = function.body().and_then(|body| body.stmt_list()) {
+        let mut stmts: VecDeque<_> = stmt_list.syntax().children_with_tokens().collect();
+
+        expect_kind(SyntaxKind::L_CURLY, stmts.pop_front(), stmt_list.syntax())?;
+        expect_kind(SyntaxKind::R_CURLY, stmts.pop_back(), stmt_list.syntax())?;
+
+        // Only render the region between `// mdbook-rust:begin` and
+        // `// mdbook-rust:end`, if there is one. The directives can also be in the
+        // comments attached to an item, like a local `fn`.
+        let mut start = TextSize::default();
+        let begin = find_directive(&stmts, "begin");
+
+        if let Some((index, comment)) = &begin {
+            start = comment.syntax().text_range().end();
+
+            if stmts[*index].as_token() == Some(comment.syntax()) {
+                stmts.drain(..=*index);
+            } else {
+                stmts.drain(..*index);
+            }
+        }
+
+        let end = find_directive(&stmts, "end");
+
+        if let Some((index, comment)) = &end {
+            // Keep any comments attached to the item before the directive.
+            let item = stmts.drain(*index..).next();
+
+            if let Some(NodeOrToken::Node(item)) = item {
+                let end = comment.syntax().text_range().start();
+                stmts.extend(
+                    item.children_with_tokens()
+                        .take_while(|child| child.text_range().end() <= end),
+                );
+            }
+        } else if let Some((_, comment)) = &begin {
+            warnings.push(directive_warning(
+                &function,
+                comment,
+                "has no matching `// mdbook-rust:end`",
+                options,
+            )?);
+        }
+
+        let used = [begin, end]
+            .into_iter()
+            .flatten()
+            .map(|(_, comment)| comment)
+            .collect::<Vec<_>>();
+
+        for comment in stmt_list
+            .syntax()
+            .descendants_with_tokens()
+            .filter_map(|element| ast::Comment::cast(element.into_token()?))
+            .filter(|comment| matches!(directive(comment), Some("begin" | "end")))
+            .filter(|comment| !used.contains(comment))
+        {
+            warnings.push(directive_warning(
+                &function,
+                &comment,
+                "isn't used",
+                options,
+            )?);
+        }
+
+        let body_text = stmts.iter().map(structural_text).collect::<String>();
+        let min_indent = || {
+            body_text
+                .lines()
+                .filter_map(whitespace_prefix)
+                .map(|indentation| indent_width(indentation, options.tab_width))
+                .min()
+        };
+        let indent = match options.dedent_strategy {
+            DedentStrategy::Min => min_indent(),
+            DedentStrategy::Common => statement_indents(&stmts, options.tab_width)
+                .min()
+                .or_else(min_indent),
+        }
+        .unwrap_or(0);
+        let fence = fence(&body_text);
+
+        let mut leading_whitespace = String::new();
+
+        if let Some(token) = stmts
+            .front()
+            .and_then(|node| node.as_token())
+            .filter(|token| ast::Whitespace::can_cast(token.kind()))
+        {
+            leading_whitespace = token.text().to_string();
+            stmts.pop_front();
+        }
+
+        Ok(Some(write_body(
+            &function,
+            stmts,
+            start,
+            indent,
+            fence,
+            &leading_whitespace,
+            options,
+        )))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Render `stmts`, leaving out anything before `start` in the comments
+/// attached to the first statement.
+fn write_body(
+    function: &ast::Fn,
+    stmts: impl IntoIterator<Item = NodeOrToken<SyntaxNode, SyntaxToken>>,
+    start: TextSize,
+    indent: usize,
+    fence: String,
+    leading_whitespace: &str,
+    options: &RenderOptions,
+) -> RenderedChapter {
+    // The line numbers are calculated from the whole file, if we need them.
+    let source_text = if options.line_numbers {
+        function
+            .syntax()
+            .ancestors()
+            .last()
+            .map_or_else(String::new, |root| root.to_string())
+    } else {
+        String::new()
+    };
+    let mut writer = BodyWriter::new(indent, fence, &source_text, options);
+    writer.start = start;
+    writer.wrappers = code_wrappers(function, options);
+    // The Markdown is about the same size as the function, so reserve that up
+    // front, rather than growing the output as we go.
+    writer
+        .output
+        .reserve(usize::from(function.syntax().text_range().len()));
+
+    // Blank lines at the start of the chapter are left out, but we keep the
+    // indentation if we're not removing it.
+    if let Some((_, indentation)) = leading_whitespace.rsplit_once('\n') {
+        writer.set_line_breaks(0, indentation);
+    }
+
+    for node in stmts {
+        writer.write_node_or_token(node);
+    }
+
+    let mut chapter = writer.finish();
+
+    if options.show_signature {
+        let signature = format!("`{}`\n", signature(function));
+
+        chapter.markdown = if chapter.markdown.is_empty() {
+            signature
+        } else {
+            format!("{signature}\n{}", chapter.markdown)
+        };
+    }
+
+    chapter
+}
+
+/// The indentation of each line in `stmts` where a statement starts, for
+/// [`DedentStrategy::Common`]. Statements starting with a label or attribute
+/// are left out, as they're often outdented.
+fn statement_indents(
+    stmts: &VecDeque<SyntaxElement>,
+    tab_width: Option<usize>,
+) -> impl Iterator<Item = usize> + '_ {
+    stmts
+        .iter()
+        .tuple_windows()
+        .filter_map(move |(before, stmt)| {
+            let (_, indentation) = before
+                .as_token()
+                .filter(|token| token.kind() == SyntaxKind::WHITESPACE)?
+                .text()
+                .rsplit_once('\n')?;
+            let first = tokens(stmt).next()?;
+
+            (!matches!(first.kind(), SyntaxKind::POUND | SyntaxKind::LIFETIME_IDENT))
+                .then(|| indent_width(indentation, tab_width))
+        })
+}
+
+/// The hidden lines that each Rust code block is wrapped in, like
+/// `fn main() {`.
+fn code_wrappers(function: &ast::Fn, options: &RenderOptions) -> Vec<String> {
+    let mut wrappers = Vec::new();
+
+    if options.wrap_main {
+        wrappers.push("fn main() {".to_string());
+    }
+
+    if options.context == CodeContext::Function {
+        wrappers.push(format!("{} {{", signature(function)));
+    }
+
+    wrappers
+}
+
+/// The signature of `function` on one line, without any attributes or
+/// comments.
+fn signature(function: &ast::Fn) -> String {
+    function
+        .syntax()
+        .children_with_tokens()
+        .skip_while(|child| {
+            matches!(
+                child.kind(),
+                SyntaxKind::COMMENT | SyntaxKind::WHITESPACE | SyntaxKind::ATTR
+            )
+        })
+        .take_while(|child| child.kind() != SyntaxKind::BLOCK_EXPR)
+        .map(|child| child.to_string())
+        .collect::<String>()
+        .split_whitespace()
+        .join(" ")
+}
+
+struct BodyWriter<'a> {
+    indent: usize,
+    fence: String,
+    /// The text of the whole file, for line numbers.
+    source_text: &'a str,
+    options: &'a RenderOptions,
+    output: String,
+    whitespace: String,
+    /// The indentation after the last line break in `whitespace`, if we're
+    /// not removing it.
+    indentation: String,
+    code_block: Option<OpenCodeBlock>,
+    code_blocks: Vec<CodeBlock>,
+    /// The language set by `// mdbook-rust:lang`, if it's not Rust.
+    lang: Option<String>,
+    /// An attribute, like `no_run`, for the next code block.
+    next_attribute: Option<String>,
+    /// The hidden lines that each Rust code block is wrapped in.
+    wrappers: Vec<String>,
+    /// Anything that ends before this is left out, like the comments before a
+    /// `// mdbook-rust:begin` that's attached to an item.
+    start: TextSize,
+    /// Fenced blocks, like Mermaid diagrams, written in comments.
+    prose_fences: Fences,
+    /// Whether the last Markdown line was the end of a fenced block.
+    after_prose_fence: bool,
+    /// A buffer for each comment's Markdown, so we don't allocate one per
+    /// comment.
+    comment_buffer: String,
+    /// The run of comments that the last comment was in.
+    comment_run: Option<CommentRun>,
+    /// The dedented source text of `whitespace`, for
+    /// [`RenderOptions::verbatim_code`].
+    verbatim_whitespace: String,
+}
+
+struct OpenCodeBlock {
+    /// Where the code block starts in the output, including any blank lines
+    /// before it.
+    start: usize,
+    /// Where the fence, or the `<details>` around it, starts in the output.
+    fence_start: usize,
+    output_start: usize,
+    source_range: TextRange,
+    /// The language, if it's not Rust.
+    lang: Option<String>,
+    /// Where the fence's info string ends in the output.
+    info_end: usize,
+    /// Whether the code uses `self`, `Self` or `super` from the entry
+    /// function's context.
+    uses_context: bool,
+    /// The output ranges of statements marked as added (`+`) or removed
+    /// (`-`). If there are any, the code block is written as a `diff`.
+    diff_lines: Vec<(Range<usize>, char)>,
+}
+
+impl<'a> BodyWriter<'a> {
+    fn new(indent: usize, fence: String, source_text: &'a str, options: &'a RenderOptions) -> Self {
+        Self {
+            indent,
+            fence,
+            source_text,
+            options,
+            output: String::new(),
+            whitespace: String::new(),
+            indentation: String::new(),
+            code_block: None,
+            code_blocks: Vec::new(),
+            lang: None,
+            next_attribute: None,
+            wrappers: Vec::new(),
+            start: TextSize::default(),
+            prose_fences: Fences::default(),
+            after_prose_fence: false,
+            comment_buffer: String::new(),
+            comment_run: None,
+            verbatim_whitespace: String::new(),
+        }
+    }
+
+    fn finish(mut self) -> RenderedChapter {
+        self.end_code_block();
+
+        // An empty body renders as an empty string.
+        if !self.output.is_empty() {
+            self.output.push('\n');
+        }
+
+        RenderedChapter {
+            headings: headings(&self.output),
+            markdown: self.output,
+            code_blocks: self.code_blocks,
+            warnings: Vec::new(),
+        }
+    }
+
+    fn write_node_or_token(&mut self, node: NodeOrToken<SyntaxNode, SyntaxToken>) {
+        if node.text_range().end() <= self.start {
+            return;
+        }
+
+        match &node {
+            NodeOrToken::Node(node) => {
+                let directive = trailing_directive(node);
+
+                if directive == Some(StatementDirective::Drop) {
+                    return;
+                }
+
+                let mut cfg_attrs = Vec::new();
+
+                if let Some(active_cfg) = &self.options.cfg {
+                    if let Some((enabled, attrs)) = eval_cfg_attrs(node, active_cfg) {
+                        if !enabled {
+                            return;
+                        }
+
+                        cfg_attrs = attrs;
+                    }
+                }
+
+                if let Some(lang) = self.lang.clone() {
+                    if let Some((source_range, text)) = string_literal(node) {
+                        // The string's contents are always dedented.
+                        self.indentation.clear();
+                        self.ensure_in_code_block(source_range, Some(&lang));
+                        self.output.push_str(&dedent(&text, self.options.tab_width));
+                        self.clear_whitespace();
+                        return;
+                    }
+                }
+
+                let mut children = node.children_with_tokens();
+                let mut code = String::new();
+                let mut first_code = None;
+
+                // `Fn` nodes will have comments associated with them, rather than the parent.
+                // We want to include these comments as markdown.
+                for child in children.by_ref() {
+                    if child.kind() == SyntaxKind::COMMENT || child.kind() == SyntaxKind::WHITESPACE
+                    {
+                        self.write_node_or_token(child);
+                    } else {
+                        first_code = Some(child);
+                        break;
+                    }
+                }
+
+                if let Some(first_code) = first_code {
+                    let range =
+                        TextRange::new(first_code.text_range().start(), node.text_range().end());
+                    self.ensure_in_code_block(range, None);
+
+                    if self.options.verbatim_code {
+                        let text = node.text().slice(range - node.text_range().start());
+                        write_verbatim(
+                            &mut code,
+                            &text.to_string(),
+                            range.start(),
+                            &string_literal_ranges(node),
+                            self.code_indent(),
+                            self.options.tab_width,
+                        );
+                    } else {
+                        for child in iter::once(first_code).chain(children) {
+                            write_code(
+                                &mut code,
+                                &child,
+                                self.code_indent(),
+                                self.options.tab_width,
+                            );
+                        }
+                    }
+                }
+
+                if let Some(code_block) = &mut self.code_block {
+                    code_block.uses_context |= self.options.auto_ignore && uses_context(node);
+                }
+
+                for attr in cfg_attrs {
+                    code = remove_leading_attr(&code, &attr);
+                }
+
+                if directive == Some(StatementDirective::Hide) {
+                    code = hide_lines(&code);
+                }
+
+                let line_start = self.output.rfind('\n').map_or(0, |index| index + 1);
+                self.output.push_str(&code);
+
+                if let (Some(marker), Some(code_block)) = (
+                    directive.and_then(StatementDirective::diff_marker),
+                    &mut self.code_block,
+                ) {
+                    code_block
+                        .diff_lines
+                        .push((line_start..self.output.len(), marker));
                 }
+
+                self.clear_whitespace();
+            }
+            NodeOrToken::Token(token) => self.write_token(token),
+        }
+    }
+
+    fn write_token(&mut self, token: &SyntaxToken) {
+        if let Some(comment) = ast::Comment::cast(token.clone()) {
+            if let Some(directive) = directive(&comment) {
+                if let Some(id) = directive.strip_prefix("anchor ") {
+                    self.ensure_in_markdown();
+                    self.output
+                        .push_str(&format!("<a id=\"{}\"></a>", id.trim()));
+                    self.clear_whitespace();
+                } else if let Some(path) = directive.strip_prefix("include ") {
+                    // The preprocessor replaces this with the included chapter.
+                    self.ensure_in_markdown();
+                    self.output.push_str(&include_marker(path.trim()));
+                    self.clear_whitespace();
+                } else if let Some(lang) = directive.strip_prefix("lang ") {
+                    let lang = lang.trim();
+                    self.lang = (lang != "rust").then(|| lang.to_string());
+                } else if CODE_BLOCK_ATTRIBUTES.contains(&directive) {
+                    // The attribute applies to the code after the directive, so start a new
+                    // code block.
+                    self.end_code_block();
+                    self.next_attribute = Some(directive.to_string());
+                    self.clear_whitespace();
+                }
+
+                // Other directives are applied to the statements they annotate.
+                return;
+            }
+
+            // Block doc comments (`/** ... */`) are written as Markdown, like non-doc
+            // comments, unless they're on the same line as some code.
+            let is_trailing = self.code_block.is_some() && !self.whitespace.contains('\n');
+            // Doc comments with code examples are written as Markdown, so the examples
+            // aren't nested in a code block.
+            let is_code_doc = is_line_doc_comment(token)
+                && self.options.doc_comments == DocComments::Code
+                && !comment_run(&mut self.comment_run, &comment, self.options).has_fence;
+
+            if is_trailing || is_code_doc {
+                // Trailing comments stay in whatever code block they're in.
+                let lang = self
+                    .code_block
+                    .as_ref()
+                    .and_then(|code_block| code_block.lang.clone());
+                self.ensure_in_code_block(token.text_range(), lang.as_deref());
+                write_lines(
+                    &mut self.output,
+                    comment.text(),
+                    self.code_indent(),
+                    self.options.tab_width,
+                );
+            } else {
+                self.ensure_in_markdown();
+                let mut markdown = mem::take(&mut self.comment_buffer);
+                markdown.clear();
+                let is_doc = comment.is_doc();
+                let gutter = comment_run(&mut self.comment_run, &comment, self.options).gutter;
+                write_comment(
+                    &mut markdown,
+                    comment,
+                    self.indent,
+                    gutter,
+                    self.options.tab_width,
+                );
+                self.push_markdown(&markdown, is_doc);
+                self.comment_buffer = markdown;
+            }
+
+            self.clear_whitespace();
+        } else if ast::Whitespace::can_cast(token.kind()) {
+            let text = token.text();
+
+            if self.options.verbatim_code {
+                self.verbatim_whitespace.clear();
+                write_verbatim(
+                    &mut self.verbatim_whitespace,
+                    text,
+                    token.text_range().start(),
+                    &[],
+                    self.code_indent(),
+                    self.options.tab_width,
+                );
+            }
+
+            // Keep whitespace between statements on the same line, but indentation after a
+            // line break is handled by the dedent.
+            match text.rsplit_once('\n') {
+                Some((_, indentation)) => {
+                    self.set_line_breaks(text.matches('\n').count(), indentation)
+                }
+                None => {
+                    self.whitespace = text.to_string();
+                    self.indentation.clear();
+                }
+            }
+        } else {
+            self.push_code_whitespace();
+            write_lines(
+                &mut self.output,
+                token.text(),
+                self.code_indent(),
+                self.options.tab_width,
+            );
+            self.clear_whitespace();
+        }
+    }
+
+    /// Write Markdown, with a blank line before and after any fenced blocks in
+    /// it, so other preprocessors can recognize them.
+    fn push_markdown(&mut self, markdown: &str, is_doc: bool) {
+        for (index, line) in markdown.split('\n').enumerate() {
+            if index > 0 {
+                self.output.push('\n');
+            }
+
+            let in_fence = self.prose_fences.open_fence_len.is_some();
+            let is_fence = self.prose_fences.is_code(line);
+
+            if (is_fence && !in_fence) || (self.after_prose_fence && !line.trim().is_empty()) {
+                self.push_blank_line();
+                self.after_prose_fence = false;
+            }
+
+            self.after_prose_fence |= in_fence && self.prose_fences.open_fence_len.is_none();
+
+            if is_fence {
+                self.output.push_str(line);
+            } else {
+                let line = doc_prose(line, is_doc, self.options);
+                write_prose_line(&mut self.output, &line, self.options.heading_offset);
+            }
+        }
+    }
+
+    /// Make sure the output ends with a blank line, unless it's empty.
+    fn push_blank_line(&mut self) {
+        if !self.output.is_empty() && !self.output.ends_with("\n\n") {
+            self.output.push_str(if self.output.ends_with('\n') {
+                "\n"
+            } else {
+                "\n\n"
+            });
+        }
+    }
+
+    fn set_line_breaks(&mut self, count: usize, indentation: &str) {
+        self.whitespace = "\n".repeat(count);
+        self.indentation.clear();
+
+        if !self.options.dedent {
+            match self.options.tab_width {
+                Some(tab_width) => expand_tabs(&mut self.indentation, indentation, tab_width),
+                None => self.indentation.push_str(indentation),
+            }
+        }
+    }
+
+    fn clear_whitespace(&mut self) {
+        self.whitespace.clear();
+        self.indentation.clear();
+        self.verbatim_whitespace.clear();
+    }
+
+    /// Write the whitespace before some code, including any indentation.
+    fn push_code_whitespace(&mut self) {
+        if self.options.verbatim_code {
+            self.output.push_str(&self.verbatim_whitespace);
+        } else {
+            self.output.push_str(&self.whitespace);
+            self.output.push_str(&self.indentation);
+        }
+    }
+
+    /// The indentation to remove from code.
+    fn code_indent(&self) -> usize {
+        if self.options.dedent {
+            self.indent
+        } else {
+            0
+        }
+    }
+
+    fn ensure_in_markdown(&mut self) {
+        if self.end_code_block() {
+            // Hidden code blocks at the start don't need a blank line after them.
+            if !self.output.is_empty() {
+                self.output.push_str("\n\n");
+            }
+        } else if !self.output.is_empty() {
+            // Blank lines at the start of the chapter are left out.
+            self.output.push_str(&self.whitespace);
+        }
+    }
+
+    /// Ensure we're in a code block for `lang`, or Rust if `lang` is `None`.
+    fn ensure_in_code_block(&mut self, source_range: TextRange, lang: Option<&str>) {
+        if let Some(code_block) = &mut self.code_block {
+            if code_block.lang.as_deref() == lang {
+                code_block.source_range = code_block.source_range.cover(source_range);
+                self.push_code_whitespace();
+                return;
+            }
+
+            self.end_code_block();
+        }
+
+        let start = self.output.len();
+
+        // There's no need for a blank line before a code block at the start.
+        if !self.output.is_empty() {
+            self.output.push_str("\n\n");
+        }
+
+        let fence_start = self.output.len();
+
+        if self.options.code == CodeDisplay::Collapse {
+            self.output
+                .push_str("<details>\n<summary>Code</summary>\n\n");
+        }
+
+        self.output.push_str(&self.fence);
+
+        let attribute = self.next_attribute.take();
+
+        match (lang, attribute) {
+            (Some(lang), _) => self.output.push_str(lang),
+            (None, Some(attribute)) => {
+                // An explicit attribute replaces `ignore`.
+                let fence_info = self.options.fence_info();
+                let attributes = fence_info
+                    .split(',')
+                    .filter(|existing| *existing != "ignore" && *existing != attribute)
+                    .chain(iter::once(attribute.as_str()))
+                    .join(",");
+                self.output.push_str(&attributes);
+            }
+            (None, None) => self.output.push_str(&self.options.fence_info()),
+        }
+
+        let info_end = self.output.len();
+        self.output.push('\n');
+
+        if lang.is_none() {
+            for wrapper in &self.wrappers {
+                self.output.push_str("# ");
+                self.output.push_str(wrapper);
+                self.output.push('\n');
+            }
+        }
+
+        self.output.push_str(&self.indentation);
+
+        self.code_block = Some(OpenCodeBlock {
+            start,
+            fence_start,
+            output_start: self.output.len(),
+            source_range,
+            lang: lang.map(str::to_string),
+            info_end,
+            uses_context: false,
+            diff_lines: Vec::new(),
+        });
+    }
+
+    /// Returns `true` if there was a code block to end.
+    fn end_code_block(&mut self) -> bool {
+        if let Some(code_block) = self.code_block.take() {
+            if self.options.code == CodeDisplay::Hide {
+                self.output.truncate(code_block.start);
+                return true;
+            }
+
+            let is_diff = !code_block.diff_lines.is_empty();
+
+            // Only Rust code blocks are reported. Diffs are reported before the lines are
+            // prefixed.
+            if code_block.lang.is_none() {
+                let source_range = code_block.source_range;
+                self.code_blocks.push(CodeBlock {
+                    code: self.output[code_block.output_start..].to_string(),
+                    source_range: usize::from(source_range.start())
+                        ..usize::from(source_range.end()),
+                });
+
+                if !is_diff {
+                    for _ in &self.wrappers {
+                        self.output.push_str("\n# }");
+                    }
+                }
+            }
+
+            if is_diff {
+                self.write_diff(&code_block);
+            }
+
+            self.output.push('\n');
+            self.output.push_str(&self.fence);
+
+            if self.options.code == CodeDisplay::Collapse {
+                self.output.push_str("\n\n</details>");
+            }
+
+            if code_block.uses_context && !is_diff {
+                let info = self.output[..code_block.info_end]
+                    .rsplit('\n')
+                    .next()
+                    .unwrap_or_default()
+                    .trim_start_matches('`');
+
+                if !info.split(',').any(|attribute| attribute == "ignore") {
+                    let separator = if info.is_empty() { "" } else { "," };
+                    self.output
+                        .insert_str(code_block.info_end, &format!("{separator}ignore"));
+                }
+            }
+
+            if self.options.line_numbers {
+                let source_range = code_block.source_range;
+                let first = line_number(self.source_text, source_range.start());
+                let last = line_number(self.source_text, source_range.end());
+                let lines = if first == last {
+                    format!("line {first}")
+                } else {
+                    format!("lines {first}-{last}")
+                };
+                self.output.insert_str(
+                    code_block.fence_start,
+                    &format!("<!-- Source {lines} -->\n"),
+                );
+            }
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Rewrite the code in `code_block` as a `diff`, with each line prefixed
+    /// by `+`, `-` or a space for unmarked context lines.
+    fn write_diff(&mut self, code_block: &OpenCodeBlock) {
+        let code = self.output.split_off(code_block.info_end);
+        let info_len = self
+            .output
+            .rsplit('\n')
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches('`')
+            .len();
+        self.output.truncate(self.output.len() - info_len);
+        self.output.push_str("diff");
+        let mut line_start = code_block.info_end;
+
+        // The first line is the end of the fence's line.
+        for (index, line) in code.split('\n').enumerate() {
+            let start = line_start;
+            line_start += line.len() + 1;
+
+            // Only Rust code is marked, so the wrapper lines are always there. mdbook
+            // only hides them in Rust code blocks, and a diff can't be run anyway, so
+            // leave them out.
+            if index == 0 || index <= self.wrappers.len() {
+                continue;
+            }
+
+            self.output.push('\n');
+
+            if !line.is_empty() {
+                let marker = code_block
+                    .diff_lines
+                    .iter()
+                    .find(|(range, _)| range.contains(&start))
+                    .map_or(' ', |(_, marker)| *marker);
+                self.output.push(marker);
             }
+
+            self.output.push_str(line);
         }
     }
-
-    Ok(None)
 }
 
-fn write_function(function: ast::Fn) -> Result<Option<String>> {
-    if let Some(stmts) = function.body().and_then(|body| body.stmt_list()) {
-        let mut stmts: VecDeque<_> = stmts.syntax().children_with_tokens().collect();
+/// A line of Markdown from a comment that isn't in a code block, with any
+/// intra-doc links written as inline code if it's from a doc comment that's
+/// rendered as Markdown.
+fn doc_prose<'a>(line: &'a str, is_doc: bool, options: &RenderOptions) -> Cow<'a, str> {
+    if !is_doc || options.doc_comments != DocComments::Markdown || !line.contains("[`") {
+        return Cow::Borrowed(line);
+    }
 
-        expect_kind(SyntaxKind::L_CURLY, stmts.pop_front())?;
-        expect_kind(SyntaxKind::R_CURLY, stmts.pop_back())?;
+    let mut output = String::with_capacity(line.len());
+    let mut rest = line;
 
-        let body_text = stmts.iter().map(|s| s.to_string()).collect::<String>();
-        let ws_prefixes = body_text.lines().filter_map(whitespace_prefix);
-        let longest_prefix = longest_prefix(ws_prefixes);
+    while let Some(start) = rest.find("[`") {
+        let after = &rest[start + 2..];
+        let link = after
+            .find("`]")
+            .map(|end| (&after[..end], &after[end + 2..]))
+            // Leave out links with a destination, like `[text][`Vec`]` or
+            // `[`Vec`](Vec)`, and link reference definitions.
+            .filter(|(path, following)| {
+                is_intra_doc_path(path)
+                    && !rest[..start].ends_with(']')
+                    && !following.starts_with(['(', '[', ':'])
+            });
 
-        if stmts
-            .front()
-            .and_then(|node| node.as_token())
-            .is_some_and(|token| ast::Whitespace::can_cast(token.kind()))
-        {
-            stmts.pop_front();
+        if let Some((path, following)) = link {
+            // Remove any disambiguator, like `struct@`.
+            let path = path.split_once('@').map_or(path, |(_, path)| path);
+            output.push_str(&rest[..start]);
+            output.push('`');
+            output.push_str(path);
+            output.push('`');
+            rest = following;
+        } else {
+            output.push_str(&rest[..start + 2]);
+            rest = after;
         }
+    }
 
-        Ok(Some(write_body(stmts, longest_prefix)))
-    } else {
-        Ok(None)
+    output.push_str(rest);
+    Cow::Owned(output)
+}
+
+/// Whether `path` could be the target of an intra-doc link, like
+/// `std::vec::Vec`, `Vec::new()`, `vec!` or `struct@Vec`.
+fn is_intra_doc_path(path: &str) -> bool {
+    !path.is_empty()
+        && path
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_:@!()<>,&".contains(c))
+}
+
+/// Write a line of Markdown that isn't in a code block, moving it down
+/// `heading_offset` levels, up to level 6, if it's a heading.
+fn write_prose_line(output: &mut String, line: &str, heading_offset: usize) {
+    let level = line.len() - line.trim_start_matches('#').len();
+    let rest = &line[level..];
+    let is_heading = (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with([' ', '\t']));
+
+    if is_heading {
+        output.extend(iter::repeat('#').take(min(level + heading_offset, 6) - level));
     }
+
+    output.push_str(line);
 }
 
-fn write_body(
-    stmts: impl IntoIterator<Item = NodeOrToken<SyntaxNode, SyntaxToken>>,
-    longest_prefix: &str,
-) -> String {
-    let mut whitespace = String::new();
-    let mut in_code_block = false;
-    let mut output = String::new();
+/// Whether `node` uses `self`, `Self` or `super` from the entry function's
+/// context, so it can't be compiled on its own.
+fn uses_context(node: &SyntaxNode) -> bool {
+    node.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| {
+            matches!(
+                token.kind(),
+                SyntaxKind::SELF_KW | SyntaxKind::SELF_TYPE_KW | SyntaxKind::SUPER_KW
+            )
+        })
+        // `self` in an `impl` or `trait` in the code refers to that item.
+        .any(|token| {
+            !token
+                .parent_ancestors()
+                .take_while(|ancestor| ancestor != node)
+                .any(|ancestor| matches!(ancestor.kind(), SyntaxKind::IMPL | SyntaxKind::TRAIT))
+        })
+}
 
-    for node in stmts {
-        write_node_or_token(
-            &mut output,
-            &mut in_code_block,
-            &mut whitespace,
-            node,
-            longest_prefix,
-        );
+/// Write `text` to `output`, removing `indent` from each line, and expanding
+/// tabs in the indentation if `tab_width` is set.
+fn write_lines(output: &mut String, text: &str, indent: usize, tab_width: Option<usize>) {
+    let mut lines = text.split('\n');
+
+    // The first line carries on from the text before it, so isn't indented.
+    if let Some(first_line) = lines.next() {
+        output.push_str(first_line);
     }
 
-    if in_code_block {
-        output.push_str("\n```");
+    for line in lines {
+        output.push('\n');
+        let line = strip_indent(line, indent, tab_width);
+
+        match tab_width {
+            Some(tab_width) => expand_tabs(output, line, tab_width),
+            None => output.push_str(line),
+        }
     }
+}
 
-    output.push('\n');
+/// Write `line` to `output`, replacing tabs in its indentation with spaces up
+/// to the next multiple of `tab_width` columns.
+fn expand_tabs(output: &mut String, line: &str, tab_width: usize) {
+    let tab_width = tab_width.max(1);
+    let mut column = 0;
+    let mut rest = "";
 
-    output
+    for (index, c) in line.char_indices() {
+        if c == '\t' {
+            column = (column / tab_width + 1) * tab_width;
+        } else if is_indentation(c) {
+            column += 1;
+        } else {
+            rest = &line[index..];
+            break;
+        }
+    }
+
+    output.extend(iter::repeat(' ').take(column));
+    output.push_str(rest);
 }
 
-fn write_node_or_token(
+/// Like [`write_lines`], but leave the contents of string literals alone.
+fn write_code(
     output: &mut String,
-    in_code_block: &mut bool,
-    whitespace: &mut String,
-    node: NodeOrToken<SyntaxNode, SyntaxToken>,
-    longest_prefix: &str,
+    element: &SyntaxElement,
+    indent: usize,
+    tab_width: Option<usize>,
 ) {
-    match &node {
-        NodeOrToken::Node(node) => {
-            let mut children = node.children_with_tokens();
-
-            // `Fn` nodes will have comments associated with them, rather than the parent.
-            // We want to include these comments as markdown.
-            for child in children.by_ref() {
-                if child.kind() == SyntaxKind::COMMENT || child.kind() == SyntaxKind::WHITESPACE {
-                    write_node_or_token(output, in_code_block, whitespace, child, longest_prefix);
-                } else {
-                    output.push_str(ensure_in_code_block(in_code_block, whitespace));
-                    output.push_str(&write_lines(child, longest_prefix));
-                    break;
-                }
+    for token in tokens(element) {
+        if is_string_literal(&token) {
+            output.push_str(token.text());
+        } else {
+            write_lines(output, token.text(), indent, tab_width);
+        }
+    }
+}
+
+/// Write source `text`, which starts at `start`, exactly as it is, apart from
+/// removing `indent` columns from the start of each line that isn't in one of
+/// the string literals in `strings`. Unlike [`write_code`], tabs aren't
+/// expanded.
+fn write_verbatim(
+    output: &mut String,
+    text: &str,
+    start: TextSize,
+    strings: &[TextRange],
+    indent: usize,
+    tab_width: Option<usize>,
+) {
+    let mut line_start = start;
+
+    for (index, line) in text.split('\n').enumerate() {
+        let in_string = strings
+            .iter()
+            .any(|string| string.start() < line_start && line_start < string.end());
+
+        if index > 0 {
+            output.push('\n');
+        }
+
+        if index == 0 || in_string {
+            output.push_str(line);
+        } else {
+            output.push_str(strip_indent(line, indent, tab_width));
+        }
+
+        line_start += TextSize::of(line) + TextSize::of('\n');
+    }
+}
+
+/// The source ranges of the string literals in `node`.
+fn string_literal_ranges(node: &SyntaxNode) -> Vec<TextRange> {
+    node.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(is_string_literal)
+        .map(|token| token.text_range())
+        .collect()
+}
+
+/// The text of `element`, with line breaks in string literals replaced by
+/// spaces, so only the lines that are indented by the code remain.
+fn structural_text(element: &SyntaxElement) -> String {
+    tokens(element)
+        .map(|token| {
+            if is_string_literal(&token) {
+                token.text().replace('\n', " ")
+            } else {
+                token.text().to_string()
+            }
+        })
+        .collect()
+}
+
+fn tokens(element: &SyntaxElement) -> impl Iterator<Item = SyntaxToken> {
+    match element {
+        NodeOrToken::Node(node) => Either::Left(
+            node.descendants_with_tokens()
+                .filter_map(|element| element.into_token()),
+        ),
+        NodeOrToken::Token(token) => Either::Right(iter::once(token.clone())),
+    }
+}
+
+fn is_string_literal(token: &SyntaxToken) -> bool {
+    matches!(token.kind(), SyntaxKind::STRING | SyntaxKind::BYTE_STRING)
+}
+
+/// The range and text of the contents of the first string literal in `node`.
+///
+/// Escapes aren't processed, as this is meant for raw strings.
+fn string_literal(node: &SyntaxNode) -> Option<(TextRange, String)> {
+    let string = node
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .find_map(ast::String::cast)?;
+    let range = string.text_range_between_quotes()?;
+    let text = &string.text()[range - string.syntax().text_range().start()];
+
+    Some((range, text.to_string()))
+}
+
+/// Remove the common indentation, and any leading or trailing blank lines,
+/// from `text`.
+fn dedent(text: &str, tab_width: Option<usize>) -> String {
+    let text = text.trim_end().trim_start_matches('\n');
+    let indent = text
+        .lines()
+        .filter_map(whitespace_prefix)
+        .map(|indentation| indent_width(indentation, tab_width))
+        .min()
+        .unwrap_or(0);
+
+    text.lines()
+        .map(|line| strip_indent(line, indent, tab_width))
+        .join("\n")
+}
+
+/// Hide each line from the rendered book, using mdbook's `# ` prefix.
+fn hide_lines(code: &str) -> String {
+    code.split('\n')
+        .map(|line| {
+            if line.is_empty() {
+                "#".to_string()
+            } else {
+                format!("# {line}")
             }
+        })
+        .join("\n")
+}
+
+fn is_line_doc_comment(token: &SyntaxToken) -> bool {
+    ast::Comment::cast(token.clone())
+        .is_some_and(|comment| comment.is_doc() && comment.kind().shape == ast::CommentShape::Line)
+}
+
+/// What we need to know about a run of comments of the same kind on
+/// consecutive lines. This is only worked out once per run, as the whole run
+/// has to be scanned.
+struct CommentRun {
+    /// The source range of the run.
+    range: TextRange,
+    /// The number of columns to remove after the `//` of each line comment.
+    gutter: usize,
+    /// Whether any of the comments start a code fence.
+    has_fence: bool,
+}
+
+impl CommentRun {
+    fn new(comment: &ast::Comment, options: &RenderOptions) -> Self {
+        let comments = run_comments(comment);
+        let range = comments
+            .iter()
+            .map(|comment| comment.syntax().text_range())
+            .fold(comment.syntax().text_range(), |run, range| run.cover(range));
+        let gutter = match options.comment_gutter {
+            CommentGutter::Spaces(spaces) => spaces,
+            // Find the smallest gutter in the run, ignoring blank lines, lines without a
+            // gutter and directives.
+            CommentGutter::Auto => comments
+                .iter()
+                .filter(|comment| directive(comment).is_none())
+                .filter_map(|comment| {
+                    whitespace_prefix(&comment.text()[comment.prefix().len()..])
+                        .map(|indentation| indent_width(indentation, options.tab_width))
+                })
+                .filter(|&width| width > 0)
+                .min()
+                .unwrap_or(0),
+        };
+
+        let has_fence = comments.iter().any(|comment| {
+            comment.text()[comment.prefix().len()..]
+                .trim_start()
+                .starts_with("```")
+        });
+
+        Self {
+            range,
+            gutter,
+            has_fence,
+        }
+    }
+}
+
+/// The run that `comment` is in, reusing the one in `cache` if it's the same.
+fn comment_run<'a>(
+    cache: &'a mut Option<CommentRun>,
+    comment: &ast::Comment,
+    options: &RenderOptions,
+) -> &'a CommentRun {
+    let start = comment.syntax().text_range().start();
+    let run = cache
+        .take()
+        .filter(|run| run.range.contains(start))
+        .unwrap_or_else(|| CommentRun::new(comment, options));
+
+    cache.insert(run)
+}
+
+/// The comments in the same run as `comment`, including `comment`.
+fn run_comments(comment: &ast::Comment) -> Vec<ast::Comment> {
+    let kind = comment.kind();
+
+    [Direction::Prev, Direction::Next]
+        .into_iter()
+        .flat_map(|direction| {
+            comment
+                .syntax()
+                .siblings_with_tokens(direction)
+                .map_while(|element| element.into_token())
+                .take_while(|token| {
+                    ast::Comment::cast(token.clone()).is_some_and(|comment| comment.kind() == kind)
+                        || (token.kind() == SyntaxKind::WHITESPACE
+                            && token.text().matches('\n').count() <= 1)
+                })
+                .filter_map(ast::Comment::cast)
+        })
+        .collect()
+}
 
-            for child in children {
-                output.push_str(&write_lines(child, longest_prefix));
+/// The directive in a comment like `// mdbook-rust:hide`.
+fn directive(comment: &ast::Comment) -> Option<&str> {
+    if comment.is_doc() || comment.kind().shape != ast::CommentShape::Line {
+        return None;
+    }
+
+    comment.text()[comment.prefix().len()..]
+        .trim()
+        .strip_prefix("mdbook-rust:")
+}
+
+/// The first comment with the directive `name`, like `// mdbook-rust:begin`,
+/// in `stmts` or the comments attached to them, and the index of the statement
+/// it's in.
+fn find_directive(stmts: &VecDeque<SyntaxElement>, name: &str) -> Option<(usize, ast::Comment)> {
+    stmts.iter().enumerate().find_map(|(index, stmt)| {
+        let mut comments = match stmt {
+            NodeOrToken::Node(node) => Either::Left(attached_comments(node)),
+            NodeOrToken::Token(token) => {
+                Either::Right(ast::Comment::cast(token.clone()).into_iter())
             }
+        };
+
+        comments
+            .find(|comment| directive(comment) == Some(name))
+            .map(|comment| (index, comment))
+    })
+}
+
+/// The comments attached to the start of `node`, like the comments just before
+/// a `fn`.
+fn attached_comments(node: &SyntaxNode) -> impl Iterator<Item = ast::Comment> {
+    node.children_with_tokens()
+        .take_while(|child| {
+            child.kind() == SyntaxKind::COMMENT || child.kind() == SyntaxKind::WHITESPACE
+        })
+        .filter_map(|child| ast::Comment::cast(child.into_token()?))
+}
 
-            whitespace.clear();
+/// A warning about a directive in `function`, or an error if
+/// [`RenderOptions::deny_warnings`] is set.
+fn directive_warning(
+    function: &ast::Fn,
+    comment: &ast::Comment,
+    problem: &str,
+    options: &RenderOptions,
+) -> Result<String> {
+    let line = function.syntax().ancestors().last().map_or(1, |root| {
+        line_number(&root.to_string(), comment.syntax().text_range().start())
+    });
+    let warning = format!("{line}: `{}` {problem}", comment.text().trim_end());
+
+    if options.deny_warnings {
+        bail!(warning);
+    }
+
+    Ok(warning)
+}
+
+/// A placeholder for an included chapter, which is an HTML comment so it's
+/// harmless if nothing replaces it.
+fn include_marker(path: &str) -> String {
+    format!("<!-- mdbook-rust:include {path} -->")
+}
+
+/// The path in a line written by [`include_marker`].
+fn parse_include_marker(line: &str) -> Option<&str> {
+    line.trim_end()
+        .strip_prefix("<!-- mdbook-rust:include ")?
+        .strip_suffix(" -->")
+}
+
+/// Directives, like `// mdbook-rust:no_run`, that add an attribute to the
+/// next code block.
+const CODE_BLOCK_ATTRIBUTES: [&str; 4] = ["no_run", "ignore", "should_panic", "compile_fail"];
+
+/// A directive in a comment on the same line as the end of a statement.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum StatementDirective {
+    /// Hide the statement using mdbook's `# ` prefix.
+    Hide,
+    /// Leave the statement out of the rendered book entirely.
+    Drop,
+    /// Show the statement as an added line in a `diff`.
+    Added,
+    /// Show the statement as a removed line in a `diff`.
+    Removed,
+}
+
+impl StatementDirective {
+    fn parse(directive: &str) -> Option<Self> {
+        match directive {
+            "hide" => Some(Self::Hide),
+            "drop" => Some(Self::Drop),
+            "+" => Some(Self::Added),
+            "-" => Some(Self::Removed),
+            _ => None,
         }
-        NodeOrToken::Token(token) => {
-            write_token(output, in_code_block, whitespace, token, longest_prefix);
+    }
+
+    fn diff_marker(self) -> Option<char> {
+        match self {
+            Self::Added => Some('+'),
+            Self::Removed => Some('-'),
+            Self::Hide | Self::Drop => None,
         }
     }
 }
 
-fn write_token(
-    output: &mut String,
-    in_code_block: &mut bool,
-    whitespace: &mut String,
-    token: &SyntaxToken,
-    longest_prefix: &str,
-) {
-    if let Some(comment) = ast::Comment::cast(token.clone()) {
-        if comment.is_doc() {
-            output.push_str(ensure_in_code_block(in_code_block, &*whitespace));
-            output.push_str(&write_lines(comment, longest_prefix));
-        } else {
-            output.push_str(ensure_in_markdown(in_code_block, &*whitespace));
-            output.push_str(&write_comment(comment, longest_prefix));
+/// Whether `function` has a `// mdbook-rust:skip` directive in the comments
+/// before it.
+fn is_skipped(function: &ast::Fn) -> bool {
+    attached_comments(function.syntax()).any(|comment| directive(&comment) == Some("skip"))
+}
+
+/// The directive in a comment on the same line as the end of `node`.
+fn trailing_directive(node: &SyntaxNode) -> Option<StatementDirective> {
+    let mut next = node.next_sibling_or_token()?;
+
+    if next.kind() == SyntaxKind::WHITESPACE {
+        if next.to_string().contains('\n') {
+            return None;
         }
 
-        whitespace.clear();
-    } else if ast::Whitespace::can_cast(token.kind()) {
-        *whitespace = "\n".repeat(token.to_string().chars().filter(|c| *c == '\n').count())
+        next = next.next_sibling_or_token()?;
+    }
+
+    let comment = ast::Comment::cast(next.into_token()?)?;
+    StatementDirective::parse(directive(&comment)?)
+}
+
+/// Evaluate the `#[cfg(...)]` attributes on a statement.
+///
+/// Returns whether the statement is enabled, and the text of the attributes,
+/// or `None` if any of them can't be evaluated.
+fn eval_cfg_attrs(node: &SyntaxNode, active_cfg: &[String]) -> Option<(bool, Vec<String>)> {
+    // Attributes on expression statements belong to the expression.
+    let attr_parent = if node.kind() == SyntaxKind::EXPR_STMT {
+        node.first_child()?
     } else {
-        output.push_str(&*whitespace);
-        output.push_str(&write_lines(token, longest_prefix));
-        whitespace.clear();
+        node.clone()
+    };
+    let mut enabled = true;
+    let mut attrs = Vec::new();
+
+    for attr in attr_parent.children().filter_map(ast::Attr::cast) {
+        let meta = attr.meta()?;
+
+        if meta.path()?.syntax().text() == "cfg" {
+            let predicate = meta.token_tree()?.syntax().to_string();
+            let predicate = predicate.strip_prefix('(')?.strip_suffix(')')?;
+            enabled &= eval_cfg(predicate, active_cfg)?;
+            attrs.push(attr.syntax().to_string());
+        }
     }
+
+    Some((enabled, attrs))
 }
 
-fn write_lines(text: impl Display, prefix: &str) -> String {
-    text.to_string()
-        .split('\n')
-        .map(|line| line.strip_prefix(prefix).unwrap_or(line))
-        .join("\n")
+/// Evaluate a `cfg` predicate, like `all(unix, not(feature = "serde"))`.
+fn eval_cfg(predicate: &str, active_cfg: &[String]) -> Option<bool> {
+    let predicate = remove_whitespace(predicate);
+    let active_cfg = active_cfg
+        .iter()
+        .map(|cfg| remove_whitespace(cfg))
+        .collect::<Vec<_>>();
+    let (enabled, rest) = eval_cfg_prefix(&predicate, &active_cfg)?;
+
+    rest.is_empty().then_some(enabled)
 }
 
-fn write_comment(comment: ast::Comment, prefix: &str) -> String {
-    let comment_suffix = &comment.text()[comment.prefix().len()..];
-    let comment_text = match comment.kind().shape {
-        ast::CommentShape::Line => comment_suffix,
-        ast::CommentShape::Block => comment_suffix.strip_suffix("*/").unwrap_or(comment_suffix),
+/// Evaluate the `cfg` predicate at the start of `predicate`, returning the
+/// result and the rest of `predicate`.
+fn eval_cfg_prefix<'a>(predicate: &'a str, active_cfg: &[String]) -> Option<(bool, &'a str)> {
+    let name_end = predicate
+        .find(|c: char| matches!(c, '(' | ',' | ')'))
+        .unwrap_or(predicate.len());
+    let (name, rest) = predicate.split_at(name_end);
+
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut rest = match rest.strip_prefix('(') {
+        Some(rest) => rest,
+        None => return Some((active_cfg.iter().any(|cfg| cfg == name), rest)),
+    };
+    let mut args = Vec::new();
+
+    while let Some((arg, arg_rest)) = eval_cfg_prefix(rest, active_cfg) {
+        args.push(arg);
+        rest = arg_rest.strip_prefix(',').unwrap_or(arg_rest);
+    }
+
+    let rest = rest.strip_prefix(')')?;
+    let enabled = match (name, args.as_slice()) {
+        ("all", args) => args.iter().all(|arg| *arg),
+        ("any", args) => args.iter().any(|arg| *arg),
+        ("not", [arg]) => !arg,
+        _ => return None,
     };
 
+    Some((enabled, rest))
+}
+
+fn remove_whitespace(text: &str) -> String {
+    text.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Remove `attr`, and the rest of its line, from the start of `code`.
+fn remove_leading_attr(code: &str, attr: &str) -> String {
+    match code.strip_prefix(attr) {
+        Some(rest) => {
+            let rest = rest.trim_start_matches(is_indentation);
+            rest.strip_prefix('\n').unwrap_or(rest).to_string()
+        }
+        None => code.to_string(),
+    }
+}
+
+/// Write `comment` as Markdown, removing `gutter` columns after the `//` if
+/// it's a line comment.
+fn write_comment(
+    output: &mut String,
+    comment: ast::Comment,
+    indent: usize,
+    gutter: usize,
+    tab_width: Option<usize>,
+) {
+    let comment_suffix = &comment.text()[comment.prefix().len()..];
+
+    match comment.kind().shape {
+        ast::CommentShape::Line => {
+            // A line with only whitespace is a blank line, whatever the gutter.
+            if !comment_suffix.trim().is_empty() {
+                output.push_str(strip_indent(comment_suffix, gutter, tab_width));
+            }
+        }
+        ast::CommentShape::Block => {
+            let comment_text = comment_suffix.strip_suffix("*/").unwrap_or(comment_suffix);
+
+            if comment.is_doc() {
+                write_block_doc_comment(output, comment_text, indent, tab_width);
+            } else {
+                write_lines(
+                    output,
+                    comment_text.strip_prefix(' ').unwrap_or(comment_text),
+                    indent,
+                    tab_width,
+                );
+            }
+        }
+    }
+}
+
+/// Write the text of a `/** ... */` comment, removing any ` * ` gutter and the
+/// blank lines after `/**` and before `*/`.
+fn write_block_doc_comment(
+    output: &mut String,
+    comment_text: &str,
+    indent: usize,
+    tab_width: Option<usize>,
+) {
     let mut lines = comment_text.split('\n');
-    let mut output = String::new();
+    let first_line = lines.next().unwrap_or_default();
+    let first_line = first_line.strip_prefix(' ').unwrap_or(first_line);
+    let other_lines = lines.collect::<Vec<_>>();
+    let has_gutter = other_lines
+        .iter()
+        .map(|line| line.trim_start())
+        .filter(|line| !line.is_empty())
+        .all(|line| line.starts_with('*'));
 
-    if let Some(first_line) = lines.next() {
-        output.push_str(first_line.strip_prefix(' ').unwrap_or(first_line));
+    let other_lines = other_lines.into_iter().map(|line| {
+        if has_gutter {
+            let line = line.trim_start();
+            let line = line.strip_prefix('*').unwrap_or(line);
+            line.strip_prefix(' ').unwrap_or(line)
+        } else {
+            strip_indent(line, indent, tab_width)
+        }
+    });
+
+    let mut lines = iter::once(first_line)
+        .chain(other_lines)
+        .collect::<Vec<_>>();
+
+    if lines.len() > 1 && lines[0].trim().is_empty() {
+        lines.remove(0);
     }
 
-    for line in lines {
-        output.push('\n');
-        output.push_str(line.strip_prefix(prefix).unwrap_or(line))
+    if let Some(last_line) = lines.last_mut() {
+        *last_line = last_line.trim_end();
     }
 
-    output
+    if lines.len() > 1 && lines.last() == Some(&"") {
+        lines.pop();
+    }
+
+    for (index, line) in lines.into_iter().enumerate() {
+        if index > 0 {
+            output.push('\n');
+        }
+
+        output.push_str(line);
+    }
 }
 
 fn parse_module(source_text: &str) -> Result<SourceFile> {
-    let parsed = SourceFile::parse(source_text);
+    // Normalize line endings, so we only have to deal with `\n` when rendering.
+    let source_text = source_text.replace("\r\n", "\n");
+    let parsed = SourceFile::parse(&source_text);
     let errors = parsed.errors();
 
     if !errors.is_empty() {
-        bail!(errors.iter().join("\n"))
+        return Err(ParseError(
+            errors
+                .iter()
+                .map(|error| {
+                    format!(
+                        "{}: {error}",
+                        line_number(&source_text, error.range().start())
+                    )
+                })
+                .join("\n"),
+        )
+        .into());
     }
 
     Ok(parsed.tree())
 }
 
-fn is_named(item: &impl HasName, name: &str) -> bool {
-    item.name().is_some_and(|n| n.text().as_ref() == name)
+/// Syntax errors in a module, one per line, each starting with a line number.
+///
+/// This is kept separate from other errors, so [`OnParseError::Skip`] only
+/// skips chapters that don't parse.
+#[derive(Debug)]
+struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-fn longest_prefix<'a>(mut prefixes: impl Iterator<Item = &'a str>) -> &'a str {
-    if let Some(mut longest_prefix) = prefixes.next() {
-        for prefix in prefixes {
-            // We can use `split_at` with `find_position` as our strings
-            // only contain single byte chars (' ' or '\t').
-            longest_prefix = longest_prefix
-                .split_at(
-                    longest_prefix
-                        .chars()
-                        .zip(prefix.chars())
-                        .find_position(|(x, y)| x != y)
-                        .map(|(position, _ch)| position)
-                        .unwrap_or_else(|| min(longest_prefix.len(), prefix.len())),
-                )
-                .0;
-        }
+impl std::error::Error for ParseError {}
 
-        longest_prefix
-    } else {
-        ""
+/// Metadata for a chapter, from directives in the comments at the start of
+/// the file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct ChapterMetadata {
+    /// Replaces the chapter name from `SUMMARY.md`.
+    title: Option<String>,
+    /// Leave the chapter's content blank.
+    draft: bool,
+    /// Tags for the chapter's front matter.
+    tags: Vec<String>,
+    /// A part title to put before the chapter, if it's a top level chapter.
+    part_title: Option<String>,
+    /// Any warnings about the entry functions, starting with a line number.
+    /// These are filled in when the chapter is rendered.
+    warnings: Vec<String>,
+}
+
+fn chapter_metadata(source: &SourceFile) -> Result<ChapterMetadata> {
+    let mut metadata = ChapterMetadata::default();
+
+    for comment in leading_comments(source) {
+        if let Some(directive) = directive(&comment) {
+            read_metadata(directive, &mut metadata).map_err(|e| {
+                let line = line_number(
+                    &source.syntax().to_string(),
+                    comment.syntax().text_range().start(),
+                );
+                anyhow!("{line}: {e}")
+            })?;
+        }
     }
+
+    Ok(metadata)
 }
 
-fn ensure_in_markdown<'a>(in_code_block: &mut bool, whitespace: &'a str) -> &'a str {
-    let text = if *in_code_block {
-        "\n```\n\n"
+/// Check for mistakes in an entry function. This returns a warning, or an
+/// error if [`RenderOptions::deny_warnings`] is set.
+fn check_entry_function(function: &ast::Fn, options: &RenderOptions) -> Result<Option<String>> {
+    let has_params = function
+        .param_list()
+        .is_some_and(|params| params.self_param().is_some() || params.params().next().is_some());
+    let problem = if function.body().and_then(|body| body.stmt_list()).is_none() {
+        // Without this, the chapter is silently left as Rust source.
+        "has no body, so there's nothing to render"
+    } else if has_params {
+        "has parameters, so it can't be called as an example"
     } else {
-        whitespace
+        return Ok(None);
     };
 
-    *in_code_block = false;
-    text
+    let name = function
+        .name()
+        .map_or_else(String::new, |name| name.to_string());
+    let line = function.syntax().ancestors().last().map_or(1, |root| {
+        line_number(&root.to_string(), function.syntax().text_range().start())
+    });
+    let warning = format!("{line}: `{name}` {problem}");
+
+    if options.deny_warnings {
+        bail!(warning);
+    }
+
+    Ok(Some(warning))
 }
 
-fn ensure_in_code_block<'a>(in_code_block: &mut bool, whitespace: &'a str) -> &'a str {
-    let text = if *in_code_block {
-        whitespace
-    } else {
-        "\n\n```rust,ignore\n"
-    };
+/// Read a metadata directive, like `title "Title"`. Other directives are
+/// ignored.
+fn read_metadata(directive: &str, metadata: &mut ChapterMetadata) -> Result<()> {
+    let (name, argument) = directive.split_once(' ').unwrap_or((directive, ""));
+
+    match name {
+        "title" => metadata.title = Some(directive_string(name, argument)?),
+        "draft" => metadata.draft = true,
+        "part" => metadata.part_title = Some(directive_string(name, argument)?),
+        "tags" => {
+            metadata.tags = directive_string(name, argument)?
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_string)
+                .collect()
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Decode the string literal argument of a directive, like `"Title"` or
+/// `r#"Title"#`.
+fn directive_string(name: &str, argument: &str) -> Result<String> {
+    let argument = argument.trim();
+    let parsed = SourceFile::parse(&format!("const _: &str = {argument};"));
+    let value = parsed
+        .tree()
+        .syntax()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .find_map(ast::String::cast)
+        .filter(|string| parsed.errors().is_empty() && string.text() == argument)
+        .and_then(|string| Some(string.value()?.into_owned()));
 
-    *in_code_block = true;
-    text
+    value.with_context(|| format!("`{name}` should be followed by a string literal"))
+}
+
+/// The comments at the start of a file, before any code, including any that
+/// are attached to the first item.
+fn leading_comments(source: &SourceFile) -> impl Iterator<Item = ast::Comment> {
+    source
+        .syntax()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .take_while(|token| {
+            token.kind() == SyntaxKind::COMMENT || token.kind() == SyntaxKind::WHITESPACE
+        })
+        .filter_map(ast::Comment::cast)
+}
+
+fn is_entry_function(function: &ast::Fn, entry_function: &str) -> bool {
+    function.name().is_some_and(|name| {
+        name.text()
+            .as_str()
+            .strip_prefix(entry_function)
+            .is_some_and(|suffix| suffix.is_empty() || suffix.starts_with('_'))
+    })
+}
+
+/// A code fence that's longer than any run of backticks in `text`, so `text`
+/// can't close it early.
+fn fence(text: &str) -> String {
+    let longest_run = text
+        .split(|c: char| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+
+    "`".repeat(max(3, longest_run + 1))
+}
+
+/// Tracks whether we're in a fenced code block, line by line.
+#[derive(Default)]
+struct Fences {
+    open_fence_len: Option<usize>,
+}
+
+impl Fences {
+    /// Returns `true` if `line` is a fence, or is in a fenced code block.
+    fn is_code(&mut self, line: &str) -> bool {
+        let backticks = line.len() - line.trim_start_matches('`').len();
+
+        if let Some(open_fence_len) = self.open_fence_len {
+            if backticks >= open_fence_len && line[backticks..].trim().is_empty() {
+                self.open_fence_len = None;
+            }
+
+            true
+        } else if backticks >= 3 {
+            self.open_fence_len = Some(backticks);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Collapse runs of blank lines outside code fences into a single blank line.
+fn collapse_blank_lines(markdown: &str) -> String {
+    let mut fences = Fences::default();
+    let mut output = String::new();
+
+    for line in markdown.split_inclusive('\n') {
+        if !fences.is_code(line) && line == "\n" && output.ends_with("\n\n") {
+            continue;
+        }
+
+        output.push_str(line);
+    }
+
+    output
+}
+
+/// The number of fenced code blocks in `markdown`.
+fn code_block_count(markdown: &str) -> usize {
+    let mut fences = Fences::default();
+
+    markdown
+        .lines()
+        .filter(|line| {
+            let in_code_block = fences.open_fence_len.is_some();
+            fences.is_code(line) && !in_code_block
+        })
+        .count()
+}
+
+/// The number of runs of Markdown, which can have blank lines in them, between
+/// fenced code blocks.
+fn prose_section_count(markdown: &str) -> usize {
+    let mut fences = Fences::default();
+    let mut in_prose = false;
+    let mut count = 0;
+
+    for line in markdown.lines() {
+        if fences.is_code(line) {
+            in_prose = false;
+        } else if !in_prose && !line.trim().is_empty() {
+            in_prose = true;
+            count += 1;
+        }
+    }
+
+    count
+}
+
+fn headings(markdown: &str) -> Vec<String> {
+    let mut fences = Fences::default();
+    let mut headings = Vec::new();
+
+    for line in markdown.lines() {
+        if !fences.is_code(line) {
+            let text = line.trim_start_matches('#');
+            let level = line.len() - text.len();
+
+            if (1..=6).contains(&level) && (text.is_empty() || text.starts_with(' ')) {
+                headings.push(text.trim().to_string());
+            }
+        }
+    }
+
+    headings
+}
+
+/// Split a level 1 heading at the start of `markdown` from the rest of it.
+fn split_first_heading(markdown: &str) -> Option<(String, String)> {
+    let markdown = markdown.trim_start_matches('\n');
+    let (line, rest) = markdown.split_once('\n').unwrap_or((markdown, ""));
+    let title = line.strip_prefix("# ")?.trim();
+
+    Some((title.to_string(), rest.trim_start_matches('\n').to_string()))
 }
 
 fn whitespace_prefix(line: &str) -> Option<&str> {
-    let non_ws = |c| c != ' ' && c != '\t';
-    line.split_once(non_ws).map(|(prefix, _)| prefix)
+    line.split_once(|c| !is_indentation(c))
+        .map(|(prefix, _)| prefix)
+}
+
+/// The width of some indentation in columns. This lets us compare indentation
+/// that mixes tabs and spaces.
+fn indent_width(indentation: &str, tab_width: Option<usize>) -> usize {
+    indentation
+        .chars()
+        .fold(0, |column, c| advance_column(column, c, tab_width))
+}
+
+/// Remove up to `indent` columns of indentation from the start of `line`.
+fn strip_indent(line: &str, indent: usize, tab_width: Option<usize>) -> &str {
+    let mut column = 0;
+
+    for (index, c) in line.char_indices() {
+        if column >= indent || !is_indentation(c) {
+            return &line[index..];
+        }
+
+        column = advance_column(column, c, tab_width);
+    }
+
+    ""
+}
+
+/// The column after `c`. Tabs advance to the next multiple of `tab_width`
+/// columns, or 4 if it isn't set.
+fn advance_column(column: usize, c: char, tab_width: Option<usize>) -> usize {
+    if c == '\t' {
+        let tab_width = tab_width.unwrap_or(4).max(1);
+        (column / tab_width + 1) * tab_width
+    } else {
+        column + 1
+    }
+}
+
+/// Any Unicode whitespace counts as indentation, except for line breaks.
+fn is_indentation(c: char) -> bool {
+    c.is_whitespace() && c != '\n' && c != '\r'
 }
 
 fn expect_kind(
     expected: SyntaxKind,
     actual: Option<NodeOrToken<SyntaxNode, SyntaxToken>>,
+    parent: &SyntaxNode,
 ) -> Result<()> {
     let actual_kind = actual
-        .and_then(|last| last.into_token())
+        .as_ref()
+        .and_then(|last| last.as_token())
         .map(|token| token.kind());
 
     if Some(expected) == actual_kind {
         Ok(())
     } else {
-        bail!("Unexpected token")
+        let range = actual.map_or_else(|| parent.text_range(), |actual| actual.text_range());
+        let source_text = parent
+            .ancestors()
+            .last()
+            .unwrap_or_else(|| parent.clone())
+            .to_string();
+
+        bail!(
+            "{}: Unexpected token, expected {expected:?}",
+            line_number(&source_text, range.start())
+        )
     }
 }
+
+/// The line number, starting at 1, of a byte offset into `source_text`.
+fn line_number(source_text: &str, offset: TextSize) -> usize {
+    source_text[..usize::from(offset)].matches('\n').count() + 1
+}
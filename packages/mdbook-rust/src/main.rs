@@ -1,32 +1,71 @@
-use std::{env, io, process};
+use std::{env, fs, io, process};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use env_logger::Env;
 use indoc::eprintdoc;
-use mdbook::{book::Chapter, preprocess::CmdPreprocessor, BookItem};
-use mdbook_rust::write_module;
-use semver::{Version, VersionReq};
+use mdbook::{
+    preprocess::{CmdPreprocessor, Preprocessor},
+    MDBOOK_VERSION,
+};
+use mdbook_rust::{diagnose, write_module_structured, RustPreprocessor};
+
+/// What the command line arguments ask us to do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Command<'a> {
+    Supports(&'a str),
+    RenderFile(&'a str),
+    Diagnose,
+    Version,
+    Preprocess,
+    Usage,
+}
+
+fn command<'a>(args: &[&'a str]) -> Command<'a> {
+    match args {
+        [_exe, "supports", renderer] => Command::Supports(renderer),
+        [_exe, "--file", path] => Command::RenderFile(path),
+        [_exe, "diagnose"] => Command::Diagnose,
+        [_exe, "--version"] => Command::Version,
+        [_exe] => Command::Preprocess,
+        _ => Command::Usage,
+    }
+}
 
 fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
     let args = Vec::from_iter(env::args());
-    match args
-        .iter()
-        .map(String::as_str)
-        .collect::<Vec<_>>()
-        .as_slice()
-    {
-        [_exe, "supports", _] => process::exit(0),
-        [_exe] => (),
-        [exe, args @ ..] => usage(exe, args),
-        args => usage("mdbook-rust", args),
-    }
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let result = match command(&args) {
+        Command::Supports(renderer) => {
+            process::exit(if RustPreprocessor.supports_renderer(renderer) {
+                0
+            } else {
+                1
+            })
+        }
+        Command::RenderFile(path) => render_file(path),
+        Command::Diagnose => print_diagnosis(),
+        Command::Version => {
+            print_version();
+            Ok(())
+        }
+        Command::Preprocess => preprocess(),
+        Command::Usage => usage(&args),
+    };
 
-    if let Err(e) = preprocess() {
+    if let Err(e) = result {
         eprintln!("{e}");
         process::exit(1);
     }
 }
 
-fn usage(exe: &str, args: &[&str]) {
+fn usage(args: &[&str]) -> ! {
+    let (exe, args) = match args {
+        [exe, args @ ..] => (*exe, args),
+        [] => ("mdbook-rust", args),
+    };
     let args = args.join(" ");
 
     eprintdoc!(
@@ -36,51 +75,81 @@ fn usage(exe: &str, args: &[&str]) {
         Usage:
             {exe}
             {exe} supports [OUTPUT_FORMAT]
+            {exe} --file [RUST_FILE]
+            {exe} diagnose
+            {exe} --version
         "
     );
     process::exit(1);
 }
 
-fn preprocess() -> Result<()> {
-    let (ctx, mut book) = CmdPreprocessor::parse_input(io::stdin())?;
+/// Print our version, and the version of mdbook we were built with, which is
+/// useful when there's more than one install around.
+fn print_version() {
+    println!("mdbook-rust {}", env!("CARGO_PKG_VERSION"));
+    println!("Built with mdbook {MDBOOK_VERSION}");
+}
 
-    let book_version = Version::parse(&ctx.mdbook_version)?;
-    let version_req = VersionReq::parse(mdbook::MDBOOK_VERSION)?;
+/// Render a single Rust file to stdout, using the default options.
+fn render_file(path: &str) -> Result<()> {
+    let source = fs::read_to_string(path).with_context(|| format!("Couldn't read {path}"))?;
+    let chapter = write_module_structured(&source)?
+        .with_context(|| format!("No `body` function found in {path}"))?;
 
-    if !version_req.matches(&book_version) {
-        eprintln!(
-            "Warning: MDBook version ({}) doesn't match plugin version ({})",
-            ctx.mdbook_version,
-            mdbook::MDBOOK_VERSION,
-        );
+    for warning in &chapter.warnings {
+        log::warn!("{path}:{warning}");
     }
 
-    let mut errors = Vec::new();
+    print!("{}", chapter.markdown);
 
-    book.for_each_mut(|item| match item {
-        BookItem::Chapter(chapter) => {
-            if let Err(e) = write_chapter(chapter) {
-                errors.push(e);
-            }
-        }
-        BookItem::Separator => (),
-        BookItem::PartTitle(_) => (),
-    });
+    Ok(())
+}
 
-    errors.into_iter().try_for_each(Err)?;
+/// Read a book from stdin, like the preprocessor, but report what would be
+/// rendered to stderr instead of rendering it.
+fn print_diagnosis() -> Result<()> {
+    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
+
+    for diagnosis in diagnose(&book, &RustPreprocessor.options(&ctx)?) {
+        eprintln!("{diagnosis}");
+    }
+
+    Ok(())
+}
+
+fn preprocess() -> Result<()> {
+    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
+    let book = RustPreprocessor.run(&ctx, book)?;
     serde_json::to_writer(io::stdout(), &book)?;
 
     Ok(())
 }
 
-fn write_chapter(chapter: &mut Chapter) -> Result<()> {
-    if let Some(path) = &chapter.path {
-        if path.extension() == Some("rs".as_ref()) {
-            if let Some(new_content) = write_module(&chapter.content)? {
-                chapter.content = new_content;
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::{command, Command};
+
+    #[test]
+    fn version() {
+        assert_eq!(command(&["mdbook-rust", "--version"]), Command::Version);
     }
 
-    Ok(())
+    #[test]
+    fn supports() {
+        assert_eq!(
+            command(&["mdbook-rust", "supports", "html"]),
+            Command::Supports("html")
+        );
+    }
+
+    #[test]
+    fn diagnose() {
+        assert_eq!(command(&["mdbook-rust", "diagnose"]), Command::Diagnose);
+    }
+
+    #[test]
+    fn usage() {
+        assert_eq!(command(&["mdbook-rust", "--unknown"]), Command::Usage);
+        assert_eq!(command(&[]), Command::Usage);
+    }
 }
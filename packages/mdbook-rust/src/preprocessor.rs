@@ -0,0 +1,905 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::{self, Display},
+    fs,
+    hash::{Hash, Hasher},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use itertools::Itertools;
+use mdbook::{
+    book::{Book, Chapter},
+    preprocess::{Preprocessor, PreprocessorContext},
+    BookItem,
+};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use semver::{Version, VersionReq};
+use serde_json::json;
+use toml::value::Table;
+
+use crate::{
+    code_block_count, headings, parse_include_marker, prose_section_count, split_first_heading,
+    write_chapter_source, write_module_with, ChapterMetadata, CodeContext, CodeDisplay,
+    CommentGutter, DedentStrategy, DocComments, Fences, FirstHeading, OnParseError, ParseError,
+    RenderOptions,
+};
+
+/// Renderers that the preprocessor runs for by default.
+///
+/// This can be overridden with mdbook's `renderers` key in
+/// `[preprocessor.rust]`.
+const SUPPORTED_RENDERERS: &[&str] = &["html"];
+
+/// An mdbook preprocessor that renders Rust source file chapters as Markdown.
+///
+/// Options are read from `[preprocessor.rust]` in `book.toml`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RustPreprocessor;
+
+/// A chapter that was rendered from a Rust source file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChapterSummary {
+    /// The chapter's path, relative to the book's source directory.
+    pub path: PathBuf,
+    /// The number of fenced code blocks in the chapter.
+    pub code_blocks: usize,
+    /// The number of headings in the chapter.
+    pub headings: usize,
+}
+
+impl RustPreprocessor {
+    /// Read the options from `[preprocessor.rust]` in `book.toml`.
+    pub fn options(&self, ctx: &PreprocessorContext) -> Result<RenderOptions> {
+        render_options(ctx)
+    }
+}
+
+impl Preprocessor for RustPreprocessor {
+    fn name(&self) -> &str {
+        "rust"
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+        let (strict_version, manifest) = match ctx.config.get_preprocessor(self.name()) {
+            Some(config) => (
+                bool_option(config, "strict-version")?.unwrap_or(false),
+                string_option(config, "manifest")?,
+            ),
+            None => (false, None),
+        };
+
+        if let Some(warning) = check_version(&ctx.mdbook_version, strict_version)? {
+            log::warn!("{warning}");
+        }
+
+        let chapters = write_book(&mut book, &self.options(ctx)?)?;
+
+        if let Some(manifest) = manifest {
+            write_manifest(&ctx.root.join(manifest), &chapters)?;
+        }
+
+        Ok(book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        SUPPORTED_RENDERERS.contains(&renderer)
+    }
+}
+
+/// Check `book_version` is compatible with the version of mdbook we were built
+/// with.
+///
+/// If it isn't, this returns a warning, or an error if `strict` is set.
+pub fn check_version(book_version: &str, strict: bool) -> Result<Option<String>> {
+    let version_req = VersionReq::parse(mdbook::MDBOOK_VERSION)?;
+
+    if version_req.matches(&Version::parse(book_version)?) {
+        return Ok(None);
+    }
+
+    let message = format!(
+        "MDBook version ({book_version}) doesn't match plugin version ({})",
+        mdbook::MDBOOK_VERSION
+    );
+
+    if strict {
+        bail!(message);
+    }
+
+    Ok(Some(message))
+}
+
+/// Render every Rust source file in `book` as Markdown.
+///
+/// With the `rayon` feature, chapters are rendered in parallel. Every chapter
+/// is rendered, even if some of them fail, and the first error is returned.
+///
+/// Returns a summary of each rendered chapter, in order.
+pub fn write_book(book: &mut Book, options: &RenderOptions) -> Result<Vec<ChapterSummary>> {
+    let mut chapters = Vec::new();
+
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            // Sub-chapters are visited separately, so we don't need to copy them.
+            let sub_items = mem::take(&mut chapter.sub_items);
+            chapters.push(chapter.clone());
+            chapter.sub_items = sub_items;
+        }
+    });
+
+    #[cfg(feature = "rayon")]
+    let chapters_iter = chapters.par_iter_mut();
+    #[cfg(not(feature = "rayon"))]
+    let chapters_iter = chapters.iter_mut();
+
+    let results: Vec<Result<ChapterOutput>> = chapters_iter
+        .map(|chapter| render_chapter(chapter, options))
+        .collect();
+
+    let part_titles: HashMap<PathBuf, String> = chapters
+        .iter()
+        .zip(&results)
+        .filter_map(|(chapter, result)| {
+            let part_title = result.as_ref().ok()?.part_title.clone()?;
+            Some((chapter.path.clone()?, part_title))
+        })
+        .collect();
+    let mut chapters = chapters.into_iter();
+
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(rendered) = chapters.next() {
+                // The name can be changed by a title directive or the first heading.
+                chapter.name = rendered.name;
+                chapter.content = rendered.content;
+            }
+        }
+    });
+
+    set_part_titles(&mut book.sections, &part_titles);
+
+    results
+        .into_iter()
+        .filter_map(|result| result.map(|rendered| rendered.summary).transpose())
+        .collect()
+}
+
+/// What the preprocessor would do with a Rust chapter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChapterDiagnosis {
+    /// The chapter's path, relative to the book's source directory.
+    pub path: PathBuf,
+    /// Whether the chapter has an entry function to render.
+    pub has_entry_function: bool,
+    /// The number of fenced code blocks the chapter would have.
+    pub code_blocks: usize,
+    /// The number of runs of Markdown between code blocks.
+    pub prose_sections: usize,
+    /// Any warnings, like an entry function with no body.
+    pub warnings: Vec<String>,
+    /// The error, if the chapter couldn't be rendered.
+    pub error: Option<String>,
+}
+
+impl Display for ChapterDiagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(error) = &self.error {
+            write!(f, "Error: {error}")
+        } else if self.has_entry_function {
+            write!(
+                f,
+                "{}: {} code blocks, {} prose sections",
+                self.path.display(),
+                self.code_blocks,
+                self.prose_sections
+            )
+        } else {
+            write!(f, "{}: No entry function", self.path.display())
+        }?;
+
+        for warning in &self.warnings {
+            write!(f, "\nWarning: {warning}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Report what would be rendered for each Rust chapter in `book`, without
+/// changing it.
+pub fn diagnose(book: &Book, options: &RenderOptions) -> Vec<ChapterDiagnosis> {
+    let mut diagnoses = Vec::new();
+
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(path) = chapter.path.as_ref().filter(|_| is_rust(chapter, options)) {
+                let mut diagnosis = ChapterDiagnosis {
+                    path: path.clone(),
+                    has_entry_function: false,
+                    code_blocks: 0,
+                    prose_sections: 0,
+                    warnings: Vec::new(),
+                    error: None,
+                };
+                match write_chapter_source(&chapter.content, options) {
+                    Ok((metadata, markdown)) => {
+                        diagnosis.warnings = metadata
+                            .warnings
+                            .iter()
+                            .map(|warning| format!("{}:{warning}", path.display()))
+                            .collect();
+
+                        if let Some(markdown) = markdown {
+                            diagnosis.has_entry_function = true;
+                            diagnosis.code_blocks = code_block_count(&markdown);
+                            diagnosis.prose_sections = prose_section_count(&markdown);
+                        }
+                    }
+                    Err(e) => diagnosis.error = Some(with_path(path, &e).to_string()),
+                }
+
+                diagnoses.push(diagnosis);
+            }
+        }
+    }
+
+    diagnoses
+}
+
+/// Put a part title before each top level chapter in `part_titles`, which maps
+/// chapter paths to the titles from their `// mdbook-rust:part "Title"`
+/// directives, replacing any part title that's already there.
+fn set_part_titles(items: &mut Vec<BookItem>, part_titles: &HashMap<PathBuf, String>) {
+    let mut index = 0;
+
+    while index < items.len() {
+        let title = match &items[index] {
+            BookItem::Chapter(chapter) => chapter
+                .path
+                .as_ref()
+                .and_then(|path| part_titles.get(path))
+                .cloned(),
+            _ => None,
+        };
+
+        if let Some(title) = title {
+            match index.checked_sub(1).map(|previous| &mut items[previous]) {
+                Some(BookItem::PartTitle(existing)) => *existing = title,
+                _ => {
+                    items.insert(index, BookItem::PartTitle(title));
+                    index += 1;
+                }
+            }
+        }
+
+        index += 1;
+    }
+}
+
+fn is_rust(chapter: &Chapter, options: &RenderOptions) -> bool {
+    chapter
+        .path
+        .as_ref()
+        .is_some_and(|path| has_rust_extension(path, options))
+}
+
+/// Whether `path` ends with one of [`RenderOptions::extensions`]. Extensions
+/// can have more than one part, like `rs.md`.
+fn has_rust_extension(path: &Path, options: &RenderOptions) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            options
+                .extensions
+                .iter()
+                .any(|extension| name.ends_with(&format!(".{extension}")))
+        })
+}
+
+/// Render `chapter` as Markdown if it's a Rust source file.
+///
+/// Chapters that aren't Rust source files are left as they are. Returns a
+/// summary of the chapter if it was rendered.
+pub fn write_chapter(
+    chapter: &mut Chapter,
+    options: &RenderOptions,
+) -> Result<Option<ChapterSummary>> {
+    Ok(render_chapter(chapter, options)?.summary)
+}
+
+/// What [`render_chapter`] found out about a chapter.
+#[derive(Default)]
+struct ChapterOutput {
+    /// A summary of the chapter, if it was rendered.
+    summary: Option<ChapterSummary>,
+    /// The title from a `// mdbook-rust:part "Title"` directive.
+    part_title: Option<String>,
+}
+
+/// Like [`write_chapter`], but also return the chapter's part title.
+fn render_chapter(chapter: &mut Chapter, options: &RenderOptions) -> Result<ChapterOutput> {
+    if let Some(path) = &chapter.path {
+        if has_rust_extension(path, options) {
+            match render_source(path, &chapter.content, options) {
+                Ok((metadata, Some(new_content))) => {
+                    let mut new_content = expand_includes(&new_content, path, options)?;
+
+                    if options.first_heading != FirstHeading::Keep {
+                        if let Some((heading, rest)) = split_first_heading(&new_content) {
+                            if options.first_heading == FirstHeading::Use {
+                                chapter.name = heading;
+                            }
+
+                            new_content = rest;
+                        }
+                    }
+
+                    // An explicit title takes precedence over the first heading.
+                    if let Some(title) = metadata.title {
+                        chapter.name = title;
+                    }
+
+                    let summary = ChapterSummary {
+                        path: path.clone(),
+                        code_blocks: code_block_count(&new_content),
+                        headings: headings(&new_content).len(),
+                    };
+
+                    chapter.content = if options.show_source_name && !metadata.draft {
+                        format!(
+                            "*{}*\n\n{}",
+                            path.display(),
+                            new_content.trim_start_matches('\n')
+                        )
+                    } else {
+                        new_content
+                    };
+
+                    if let Some(base) = &options.source_link_base {
+                        if !metadata.draft {
+                            chapter.content = format!(
+                                "{}\n\n[View source]({}/{})\n",
+                                chapter.content.trim_end(),
+                                base.trim_end_matches('/'),
+                                path.iter().map(|part| part.to_string_lossy()).join("/")
+                            );
+                        }
+                    }
+
+                    if options.front_matter {
+                        chapter.content =
+                            front_matter(&chapter.name, &metadata.tags) + &chapter.content;
+                    }
+
+                    return Ok(ChapterOutput {
+                        summary: Some(summary),
+                        part_title: metadata.part_title,
+                    });
+                }
+                Ok((metadata, None)) => {
+                    return Ok(ChapterOutput {
+                        summary: None,
+                        part_title: metadata.part_title,
+                    })
+                }
+                Err(e) => {
+                    let is_parse_error = e.is::<ParseError>();
+                    let e = with_path(path, &e);
+
+                    if options.on_parse_error == OnParseError::Skip && is_parse_error {
+                        log::warn!("Skipping chapter:\n{e}");
+                        chapter.content = parse_error_warning(&e) + &chapter.content;
+                    } else {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(ChapterOutput::default())
+}
+
+/// YAML front matter for a chapter. Strings are written as JSON, which is
+/// valid YAML.
+fn front_matter(title: &str, tags: &[String]) -> String {
+    let mut front_matter = format!("---\ntitle: {}\n", json!(title));
+
+    if !tags.is_empty() {
+        front_matter.push_str(&format!("tags: {}\n", json!(tags)));
+    }
+
+    // The blank line keeps the closing `---` from turning the line before it
+    // into a heading.
+    front_matter.push_str("---\n\n");
+    front_matter
+}
+
+/// Replace `// mdbook-rust:include` placeholders in the Markdown for
+/// `chapter_path` with the included chapters.
+fn expand_includes(markdown: &str, chapter_path: &Path, options: &RenderOptions) -> Result<String> {
+    let file = match &options.source_dir {
+        Some(source_dir) => source_dir.join(chapter_path),
+        None => chapter_path.to_path_buf(),
+    };
+
+    expand_includes_from(markdown, &file, &mut vec![canonical_path(&file)], options)
+}
+
+/// `includers` are the files that are currently being included, so we can
+/// detect cycles.
+fn expand_includes_from(
+    markdown: &str,
+    file: &Path,
+    includers: &mut Vec<PathBuf>,
+    options: &RenderOptions,
+) -> Result<String> {
+    let dir = file.parent().unwrap_or(Path::new(""));
+    let mut fences = Fences::default();
+    let mut output = String::new();
+
+    for line in markdown.split_inclusive('\n') {
+        let is_code = fences.is_code(line);
+        let include = match parse_include_marker(line) {
+            Some(include) if !is_code => include,
+            _ => {
+                output.push_str(line);
+                continue;
+            }
+        };
+
+        let include = dir.join(include);
+        let canonical_include = canonical_path(&include);
+
+        if includers.contains(&canonical_include) {
+            bail!(
+                "Include cycle: {} -> {}",
+                includers.iter().map(|path| path.display()).join(" -> "),
+                canonical_include.display()
+            );
+        }
+
+        let source = fs::read_to_string(&include)
+            .with_context(|| format!("Couldn't read {}", include.display()))?;
+        let included = write_module_with(&source, options)
+            .map_err(|e| with_path(&include, &e))?
+            .unwrap_or_default();
+
+        includers.push(canonical_include);
+        let included = expand_includes_from(&included, &include, includers, options)?;
+        includers.pop();
+
+        output.push_str(included.trim_end_matches('\n'));
+
+        if line.ends_with('\n') {
+            output.push('\n');
+        }
+    }
+
+    Ok(output)
+}
+
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Write a JSON manifest of the rendered chapters to `path`, for other tools to
+/// use.
+fn write_manifest(path: &Path, chapters: &[ChapterSummary]) -> Result<()> {
+    let chapters = chapters
+        .iter()
+        .map(|chapter| {
+            json!({
+                "path": chapter.path,
+                "code_blocks": chapter.code_blocks,
+                "headings": chapter.headings,
+            })
+        })
+        .collect::<Vec<_>>();
+    let manifest = serde_json::to_string_pretty(&json!({ "chapters": chapters }))?;
+
+    fs::write(path, manifest)
+        .with_context(|| format!("Couldn't write manifest to {}", path.display()))
+}
+
+/// Render a chapter's source, along with its metadata. Drafts are rendered as
+/// an empty string. The Markdown is `None` if there are no entry functions.
+fn render_source(
+    path: &Path,
+    source_text: &str,
+    options: &RenderOptions,
+) -> Result<(ChapterMetadata, Option<String>)> {
+    let (metadata, markdown) = write_chapter_cached(source_text, options)?;
+
+    for warning in &metadata.warnings {
+        log::warn!("{}:{warning}", path.display());
+    }
+
+    Ok((metadata, markdown))
+}
+
+/// Like [`write_chapter_source`], but use [`RenderOptions::cache_dir`] if it's
+/// set. The metadata is cached along with the Markdown, so unchanged chapters
+/// aren't parsed at all.
+fn write_chapter_cached(
+    source_text: &str,
+    options: &RenderOptions,
+) -> Result<(ChapterMetadata, Option<String>)> {
+    let cache_dir = match &options.cache_dir {
+        Some(cache_dir) => cache_dir,
+        None => return write_chapter_source(source_text, options),
+    };
+    let cache_file = cache_dir.join(format!("{:016x}.json", cache_key(source_text, options)));
+
+    if let Some(cached) = fs::read_to_string(&cache_file)
+        .ok()
+        .and_then(|entry| read_cache_entry(&entry))
+    {
+        return Ok(cached);
+    }
+
+    let (metadata, markdown) = write_chapter_source(source_text, options)?;
+    let entry = json!({
+        "title": metadata.title,
+        "draft": metadata.draft,
+        "tags": metadata.tags,
+        "part_title": metadata.part_title,
+        "warnings": metadata.warnings,
+        "markdown": markdown,
+    });
+
+    // The cache is just an optimization, so we carry on if we can't write to it.
+    if let Err(e) =
+        fs::create_dir_all(cache_dir).and_then(|()| fs::write(&cache_file, entry.to_string()))
+    {
+        log::warn!("Couldn't write to cache: {e}");
+    }
+
+    Ok((metadata, markdown))
+}
+
+/// Read a cache entry written by [`write_chapter_cached`]. Entries that can't
+/// be read are treated as missing.
+fn read_cache_entry(entry: &str) -> Option<(ChapterMetadata, Option<String>)> {
+    let entry: serde_json::Value = serde_json::from_str(entry).ok()?;
+    let strings = |key: &str| {
+        entry
+            .get(key)?
+            .as_array()?
+            .iter()
+            .map(|value| value.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+    };
+    let optional_string = |key: &str| match entry.get(key)? {
+        serde_json::Value::Null => Some(None),
+        value => Some(Some(value.as_str()?.to_string())),
+    };
+    let metadata = ChapterMetadata {
+        title: optional_string("title")?,
+        draft: entry.get("draft")?.as_bool()?,
+        tags: strings("tags")?,
+        part_title: optional_string("part_title")?,
+        warnings: strings("warnings")?,
+    };
+
+    Some((metadata, optional_string("markdown")?))
+}
+
+/// The options are part of the key, so changing the config invalidates the
+/// cache. So does upgrading, as rendering might have changed.
+fn cache_key(source_text: &str, options: &RenderOptions) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    options.hash(&mut hasher);
+    source_text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Prefix each line of `error`, which start with a line number, with `path`.
+fn with_path(path: &Path, error: &Error) -> Error {
+    anyhow!(error
+        .to_string()
+        .lines()
+        .map(|line| format!("{}:{line}", path.display()))
+        .join("\n"))
+}
+
+fn parse_error_warning(error: &Error) -> String {
+    let mut warning =
+        String::from("> **Warning:** This chapter couldn't be rendered:\n>\n> ```text\n");
+
+    for line in error.to_string().lines() {
+        warning.push_str("> ");
+        warning.push_str(line);
+        warning.push('\n');
+    }
+
+    warning.push_str("> ```\n\n");
+    warning
+}
+
+impl RenderOptions {
+    /// Read the options from a `[preprocessor.rust]` table in `book.toml`.
+    ///
+    /// Paths, like `cache-dir`, are left relative to the book's root. Unknown
+    /// keys, which are probably typos, are reported with a warning.
+    pub fn from_config(config: Option<&Table>) -> Result<Self> {
+        let mut options = Self::default();
+
+        if let Some(config) = config {
+            for key in unknown_config_keys(config) {
+                log::warn!("Unknown `preprocessor.rust` option `{key}`");
+            }
+
+            if let Some(code_attributes) = string_option(config, "code-attributes")? {
+                options.code_attributes = code_attributes;
+            }
+
+            if let Some(editable) = bool_option(config, "editable")? {
+                options.editable = editable;
+            }
+
+            if let Some(wrap_main) = bool_option(config, "wrap-main")? {
+                options.wrap_main = wrap_main;
+            }
+
+            if let Some(auto_ignore) = bool_option(config, "auto-ignore")? {
+                options.auto_ignore = auto_ignore;
+            }
+
+            if let Some(context) = string_option(config, "context")? {
+                options.context = match context.as_str() {
+                    "none" => CodeContext::None,
+                    "function" => CodeContext::Function,
+                    _ => bail!("`context` should be \"none\" or \"function\""),
+                };
+            }
+
+            if let Some(show_source_name) = bool_option(config, "show-source-name")? {
+                options.show_source_name = show_source_name;
+            }
+
+            if let Some(base) = string_option(config, "source-link-base")? {
+                options.source_link_base = Some(base).filter(|base| !base.is_empty());
+            }
+
+            if let Some(first_heading) = string_option(config, "title-from-first-heading")? {
+                options.first_heading = match first_heading.as_str() {
+                    "keep" => FirstHeading::Keep,
+                    "use" => FirstHeading::Use,
+                    "strip" => FirstHeading::Strip,
+                    _ => {
+                        bail!("`title-from-first-heading` should be \"keep\", \"use\" or \"strip\"")
+                    }
+                };
+            }
+
+            if let Some(include_tests) = bool_option(config, "include-tests")? {
+                options.include_tests = include_tests;
+            }
+
+            if let Some(dedent) = bool_option(config, "dedent")? {
+                options.dedent = dedent;
+            }
+
+            if let Some(strategy) = string_option(config, "dedent-strategy")? {
+                options.dedent_strategy = match strategy.as_str() {
+                    "min" => DedentStrategy::Min,
+                    "common" => DedentStrategy::Common,
+                    _ => bail!("`dedent-strategy` should be \"min\" or \"common\""),
+                };
+            }
+
+            if let Some(verbatim_code) = bool_option(config, "verbatim-code")? {
+                options.verbatim_code = verbatim_code;
+            }
+
+            if let Some(heading_offset) = config.get("heading-offset") {
+                options.heading_offset = heading_offset
+                    .as_integer()
+                    .and_then(|offset| usize::try_from(offset).ok())
+                    .context("`heading-offset` should be a non-negative integer")?;
+            }
+
+            if let Some(tab_width) = usize_option(config, "tab-width")? {
+                options.tab_width = Some(tab_width);
+            }
+
+            if let Some(line_numbers) = bool_option(config, "line-numbers")? {
+                options.line_numbers = line_numbers;
+            }
+
+            if let Some(show_signature) = bool_option(config, "show-signature")? {
+                options.show_signature = show_signature;
+            }
+
+            if let Some(front_matter) = bool_option(config, "front-matter")? {
+                options.front_matter = front_matter;
+            }
+
+            if let Some(deny_warnings) = bool_option(config, "deny-warnings")? {
+                options.deny_warnings = deny_warnings;
+            }
+
+            if let Some(cache_dir) = string_option(config, "cache-dir")? {
+                options.cache_dir = Some(PathBuf::from(cache_dir));
+            }
+
+            if let Some(extensions) = string_list_option(config, "extensions")? {
+                options.extensions = extensions;
+            }
+
+            if let Some(cfg) = string_list_option(config, "cfg")? {
+                options.cfg = Some(cfg);
+            }
+
+            if let Some(entry_function) = string_option(config, "entry-function")? {
+                options.entry_function = entry_function;
+            }
+
+            if let Some(whole_file) = bool_option(config, "whole-file")? {
+                options.whole_file = whole_file;
+            }
+
+            if let Some(doc_comments) = string_option(config, "doc-comments")? {
+                options.doc_comments = match doc_comments.as_str() {
+                    "code" => DocComments::Code,
+                    "markdown" => DocComments::Markdown,
+                    _ => bail!("`doc-comments` should be \"code\" or \"markdown\""),
+                };
+            }
+
+            if let Some(gutter) = config.get("comment-gutter") {
+                options.comment_gutter = if gutter.as_str() == Some("auto") {
+                    CommentGutter::Auto
+                } else {
+                    CommentGutter::Spaces(
+                        gutter
+                            .as_integer()
+                            .and_then(|spaces| usize::try_from(spaces).ok())
+                            .context("`comment-gutter` should be \"auto\" or a number of spaces")?,
+                    )
+                };
+            }
+
+            if let Some(on_parse_error) = string_option(config, "on-parse-error")? {
+                options.on_parse_error = match on_parse_error.as_str() {
+                    "fail" => OnParseError::Fail,
+                    "skip" => OnParseError::Skip,
+                    _ => bail!("`on-parse-error` should be \"fail\" or \"skip\""),
+                };
+            }
+
+            if let Some(code) = string_option(config, "code")? {
+                options.code = match code.as_str() {
+                    "show" => CodeDisplay::Show,
+                    "hide" => CodeDisplay::Hide,
+                    "collapse" => CodeDisplay::Collapse,
+                    _ => bail!("`code` should be \"show\", \"hide\" or \"collapse\""),
+                };
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// The keys read by [`RenderOptions::from_config`].
+const CONFIG_KEYS: &[&str] = &[
+    "code-attributes",
+    "editable",
+    "wrap-main",
+    "auto-ignore",
+    "context",
+    "show-source-name",
+    "source-link-base",
+    "title-from-first-heading",
+    "include-tests",
+    "dedent",
+    "dedent-strategy",
+    "verbatim-code",
+    "heading-offset",
+    "tab-width",
+    "line-numbers",
+    "show-signature",
+    "front-matter",
+    "deny-warnings",
+    "cache-dir",
+    "extensions",
+    "cfg",
+    "entry-function",
+    "whole-file",
+    "doc-comments",
+    "comment-gutter",
+    "on-parse-error",
+    "code",
+];
+
+/// Keys in `[preprocessor.rust]` that are read by mdbook, or by the
+/// preprocessor itself, rather than [`RenderOptions::from_config`].
+const OTHER_CONFIG_KEYS: &[&str] = &[
+    "command",
+    "renderers",
+    "before",
+    "after",
+    "optional",
+    "strict-version",
+    "manifest",
+];
+
+/// The keys in a `[preprocessor.rust]` table that aren't options, which are
+/// probably typos.
+pub fn unknown_config_keys(config: &Table) -> Vec<String> {
+    config
+        .keys()
+        .filter(|key| {
+            !CONFIG_KEYS.contains(&key.as_str()) && !OTHER_CONFIG_KEYS.contains(&key.as_str())
+        })
+        .cloned()
+        .collect()
+}
+
+fn render_options(ctx: &PreprocessorContext) -> Result<RenderOptions> {
+    let mut options =
+        RenderOptions::from_config(ctx.config.get_preprocessor(RustPreprocessor.name()))?;
+    options.source_dir = Some(ctx.root.join(&ctx.config.book.src));
+    options.cache_dir = options.cache_dir.map(|cache_dir| ctx.root.join(cache_dir));
+
+    Ok(options)
+}
+
+fn string_option(config: &Table, key: &str) -> Result<Option<String>> {
+    config
+        .get(key)
+        .map(|value| {
+            value
+                .as_str()
+                .map(str::to_string)
+                .with_context(|| format!("`{key}` should be a string"))
+        })
+        .transpose()
+}
+
+fn string_list_option(config: &Table, key: &str) -> Result<Option<Vec<String>>> {
+    config
+        .get(key)
+        .map(|value| {
+            value
+                .as_array()
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|value| value.as_str().map(str::to_string))
+                        .collect()
+                })
+                .with_context(|| format!("`{key}` should be a list of strings"))
+        })
+        .transpose()
+}
+
+fn usize_option(config: &Table, key: &str) -> Result<Option<usize>> {
+    config
+        .get(key)
+        .map(|value| {
+            value
+                .as_integer()
+                .and_then(|value| usize::try_from(value).ok())
+                .filter(|value| *value > 0)
+                .with_context(|| format!("`{key}` should be a positive integer"))
+        })
+        .transpose()
+}
+
+fn bool_option(config: &Table, key: &str) -> Result<Option<bool>> {
+    config
+        .get(key)
+        .map(|value| {
+            value
+                .as_bool()
+                .with_context(|| format!("`{key}` should be a boolean"))
+        })
+        .transpose()
+}
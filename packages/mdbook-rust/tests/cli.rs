@@ -0,0 +1,80 @@
+use std::{env, fs, process::Command};
+
+use mdbook::book::Chapter;
+use mdbook_rust::{write_chapter, write_module, RenderOptions};
+
+fn supports(renderer: &str) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_mdbook-rust"))
+        .args(["supports", renderer])
+        .status()
+        .unwrap()
+        .success()
+}
+
+#[test]
+fn supports_html() {
+    assert!(supports("html"));
+}
+
+#[test]
+fn doesnt_support_other_renderers() {
+    assert!(!supports("linkcheck"));
+}
+
+#[test]
+fn version() {
+    let output = Command::new(env!("CARGO_BIN_EXE_mdbook-rust"))
+        .arg("--version")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "mdbook-rust {}\nBuilt with mdbook {}\n",
+            env!("CARGO_PKG_VERSION"),
+            mdbook::MDBOOK_VERSION
+        )
+    );
+}
+
+/// `--file`, the library and the preprocessor should all render the same
+/// Markdown.
+#[test]
+fn entry_points_match() {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../../examples/book/src/chapter1.rs"
+    );
+    let source = fs::read_to_string(path).unwrap();
+    let library = write_module(&source).unwrap().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_mdbook-rust"))
+        .args(["--file", path])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), library);
+
+    let mut chapter = Chapter::new("Chapter 1", source, "chapter1.rs", Vec::new());
+    write_chapter(&mut chapter, &RenderOptions::default()).unwrap();
+    assert_eq!(chapter.content, library);
+}
+
+#[test]
+fn file_warnings() {
+    let path = env::temp_dir().join("mdbook-rust-file-warnings.rs");
+    fs::write(&path, "fn body(x: i32) {\n    let y = x;\n}\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_mdbook-rust"))
+        .arg("--file")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let warning = format!(
+        "{}:1: `body` has parameters, so it can't be called as an example",
+        path.display()
+    );
+    assert!(String::from_utf8(output.stderr).unwrap().contains(&warning));
+}
@@ -1,15 +1,44 @@
 use indoc::indoc;
-use mdbook_rust::write_module;
+use mdbook_rust::{
+    render_module, write_module, write_module_structured, write_module_structured_with,
+    write_module_with, CodeBlock, CodeContext, CodeDisplay, CommentGutter, DedentStrategy,
+    DocComments, RenderOptions, RenderedChapter,
+};
 
 fn check(source: &str, expected: &str) {
     assert_eq!(write_module(source).unwrap(), Some(expected.to_string()));
 }
 
+fn check_with(source: &str, expected: &str, options: &RenderOptions) {
+    assert_eq!(
+        write_module_with(source, options).unwrap(),
+        Some(expected.to_string())
+    );
+}
+
 #[test]
 fn empty() {
     assert!(write_module("").unwrap().is_none());
 }
 
+#[test]
+fn render_to_writer() {
+    let source = indoc! {"
+        fn body() {
+            // Text
+            let x = 1;
+        }
+    "};
+    let mut out = Vec::new();
+
+    assert!(render_module(source, &mut out, &RenderOptions::default()).unwrap());
+    assert_eq!(out, write_module(source).unwrap().unwrap().into_bytes());
+
+    out.clear();
+    assert!(!render_module("", &mut out, &RenderOptions::default()).unwrap());
+    assert!(out.is_empty());
+}
+
 #[test]
 fn ignored() {
     assert!(write_module(indoc! {"
@@ -44,7 +73,25 @@ fn basic() {
 
 #[test]
 fn empty_body() {
-    check("fn body() {}", "\n")
+    check("fn body() {}", "")
+}
+
+#[test]
+fn only_prose() {
+    check(
+        indoc! {"
+            fn body() {
+                // # Only prose
+                //
+                // A paragraph
+            }
+        "},
+        indoc! {"
+            # Only prose
+
+            A paragraph
+        "},
+    )
 }
 
 #[test]
@@ -69,6 +116,27 @@ fn line_comment_indent() {
     )
 }
 
+#[test]
+fn blank_line_comments() {
+    // A bare `//` and one with trailing whitespace are both blank lines.
+    check("fn body() {\n    // A\n    //\n    // B\n}\n", "A\n\nB\n");
+    check("fn body() {\n    // A\n    //  \n    // B\n}\n", "A\n\nB\n");
+    check_with(
+        "fn body() {\n    //  A\n    //  \n    //  B\n}\n",
+        "  A\n\n  B\n",
+        &RenderOptions {
+            comment_gutter: CommentGutter::Spaces(0),
+            ..RenderOptions::default()
+        },
+    );
+}
+
+#[test]
+fn tab_after_comment_marker() {
+    // A tab after `//` is part of the gutter, like a space.
+    check("fn body() {\n    //\tA\n    //\t\tB\n}\n", "A\n\tB\n");
+}
+
 #[test]
 fn block_comment_indent() {
     check(
@@ -105,8 +173,6 @@ fn code_only() {
             }
         "},
         indoc! {"
-
-
             ```rust,ignore
             let x = 1;
             let y = 1;
@@ -126,8 +192,6 @@ fn code_spacing() {
             }
         "},
         indoc! {"
-
-
             ```rust,ignore
             let x = 1;
 
@@ -155,3 +219,1905 @@ fn local_function() {
         "},
     )
 }
+
+#[test]
+fn default_code_attributes() {
+    assert_eq!(RenderOptions::default().code_attributes, "rust,ignore");
+}
+
+#[test]
+fn custom_code_attributes() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Text
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            Text
+
+            ```rust,no_run
+            let x = 1;
+            ```
+        "},
+        &RenderOptions {
+            code_attributes: "rust,no_run".to_string(),
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn structured() {
+    let chapter = write_module_structured(indoc! {"
+        fn body() {
+            // # Title
+            //
+            // Body text
+            let x = 1;
+        }
+    "})
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(
+        chapter,
+        RenderedChapter {
+            markdown: indoc! {"
+                # Title
+
+                Body text
+
+                ```rust,ignore
+                let x = 1;
+                ```
+            "}
+            .to_string(),
+            code_blocks: vec![CodeBlock {
+                code: "let x = 1;".to_string(),
+                source_range: 55..65,
+            }],
+            headings: vec!["Title".to_string()],
+            warnings: Vec::new(),
+        }
+    );
+}
+
+#[test]
+fn entry_function_with_parameters() {
+    let source = indoc! {"
+        fn body(x: i32) {
+            let y = x;
+        }
+    "};
+    let warning = "1: `body` has parameters, so it can't be called as an example";
+
+    let chapter = write_module_structured(source).unwrap().unwrap();
+    assert_eq!(chapter.warnings, [warning]);
+
+    let error = write_module_structured_with(
+        source,
+        &RenderOptions {
+            deny_warnings: true,
+            ..RenderOptions::default()
+        },
+    )
+    .unwrap_err();
+    assert_eq!(error.to_string(), warning);
+}
+
+#[test]
+fn hidden_statement() {
+    check(
+        indoc! {"
+            fn body() {
+                use std::fmt; // mdbook-rust:hide
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            # use std::fmt;
+            let x = 1;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn dropped_statement() {
+    check(
+        indoc! {"
+            fn body() {
+                let x = 1;
+                let _ = env_logger::try_init(); // mdbook-rust:drop
+                let y = 2;
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            let y = 2;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn diff_statements() {
+    check(
+        indoc! {"
+            fn body() {
+                let x = 1;
+                let y = 2; // mdbook-rust:-
+                let y = 3; // mdbook-rust:+
+            }
+        "},
+        indoc! {"
+            ```diff
+             let x = 1;
+            -let y = 2;
+            +let y = 3;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn diff_with_wrappers() {
+    let chapter = write_module_structured_with(
+        indoc! {"
+            fn body() {
+                let x = 1; // mdbook-rust:-
+                let x = 2; // mdbook-rust:+
+            }
+        "},
+        &RenderOptions {
+            wrap_main: true,
+            context: CodeContext::Function,
+            ..RenderOptions::default()
+        },
+    )
+    .unwrap()
+    .unwrap();
+
+    // The hidden wrappers are left out of the diff, but it's still reported as a
+    // code block.
+    assert_eq!(
+        chapter.markdown,
+        indoc! {"
+            ```diff
+            -let x = 1;
+            +let x = 2;
+            ```
+        "}
+    );
+    assert_eq!(
+        chapter.code_blocks,
+        [CodeBlock {
+            code: "let x = 1;\nlet x = 2;".to_string(),
+            source_range: 16..58,
+        }]
+    );
+}
+
+#[test]
+fn crlf_line_endings() {
+    check(
+        "fn body() {\r\n    // Text\r\n    let x = 1;\r\n\r\n    let y = 2;\r\n}\r\n",
+        indoc! {"
+            Text
+
+            ```rust,ignore
+            let x = 1;
+
+            let y = 2;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn multiple_entry_functions() {
+    check(
+        indoc! {"
+            fn body_intro() {
+                // Introduction
+            }
+
+            fn ignored() {
+                // Ignored
+            }
+
+            fn body_details() {
+                // Details
+            }
+        "},
+        indoc! {"
+            Introduction
+
+            Details
+        "},
+    )
+}
+
+#[test]
+fn custom_entry_function() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Ignored
+            }
+
+            fn chapter() {
+                // Chapter
+            }
+        "},
+        indoc! {"
+            Chapter
+        "},
+        &RenderOptions {
+            entry_function: "chapter".to_string(),
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn unicode_indent() {
+    check(
+        indoc! {"
+            fn body() {
+                /*
+                \u{3000}Indented with an ideographic space
+                */
+                let x = 1;
+            }
+        "},
+        "\n\u{3000}Indented with an ideographic space\n\n```rust,ignore\nlet x = 1;\n```\n",
+    )
+}
+
+/// Code indented with a mix of spaces and a multi-byte whitespace character
+/// that Rust allows, U+0085, is dedented by columns, not bytes.
+#[test]
+fn mixed_unicode_indent() {
+    check(
+        concat!(
+            "fn body() {\n",
+            "\u{85}   let x = 1;\n",
+            "  \u{85} if x == 1 {\n",
+            "  \u{85}     let y = 2;\n",
+            " \u{85}  }\n",
+            "}\n",
+        ),
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            if x == 1 {
+                let y = 2;
+            }
+            ```
+        "},
+    )
+}
+
+#[test]
+fn inner_doc_comments() {
+    check(
+        indoc! {"
+            //! # Chapter
+            //!
+            //! Introduction.
+
+            // Ignored
+            fn body() {
+                // Text
+            }
+        "},
+        indoc! {"
+            # Chapter
+
+            Introduction.
+
+            Text
+        "},
+    )
+}
+
+#[test]
+fn comment_attached_to_local_function() {
+    check(
+        indoc! {"
+            fn body() {
+                let x = 1;
+                // Explanatory text
+                fn local() {}
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            Explanatory text
+
+            ```rust,ignore
+            fn local() {}
+            ```
+        "},
+    )
+}
+
+#[test]
+fn single_line_block_doc_comment() {
+    check(
+        indoc! {"
+            fn body() {
+                /** # Section */
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            # Section
+
+            ```rust,ignore
+            let x = 1;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn multi_line_block_doc_comment() {
+    check(
+        indoc! {"
+            fn body() {
+                /**
+                 * # Section
+                 *
+                 * Text
+                 */
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            # Section
+
+            Text
+
+            ```rust,ignore
+            let x = 1;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn editable() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Text
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            Text
+
+            ```rust,editable
+            let x = 1;
+            ```
+        "},
+        &RenderOptions {
+            editable: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn editable_wrapped_in_main() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Text
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            Text
+
+            ```rust,editable
+            # fn main() {
+            let x = 1;
+            # }
+            ```
+        "},
+        &RenderOptions {
+            editable: true,
+            wrap_main: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn leading_blank_lines() {
+    check(
+        indoc! {"
+            fn body() {
+
+
+                // Text
+
+                // More
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            Text
+
+            More
+
+            ```rust,ignore
+            let x = 1;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn mixed_tabs_and_spaces() {
+    check(
+        "fn body() {\n\t// Text\n    let x = 1;\n\tif x == 1 {\n\t    x;\n\t}\n}\n",
+        indoc! {"
+            Text
+
+            ```rust,ignore
+            let x = 1;
+            if x == 1 {
+                x;
+            }
+            ```
+        "},
+    )
+}
+
+#[test]
+fn skipped_entry_function() {
+    check(
+        indoc! {"
+            // mdbook-rust:skip
+            fn body() {
+                // Skipped
+            }
+
+            fn body_rendered() {
+                // Text
+            }
+        "},
+        "Text\n",
+    )
+}
+
+#[test]
+fn collapse_blank_lines() {
+    check(
+        indoc! {"
+            fn body() {
+                // First
+
+
+
+                // Second
+                let x = 1;
+
+
+                let y = 2;
+                /*
+                Third
+                */
+                let z = 3;
+            }
+        "},
+        indoc! {"
+            First
+
+            Second
+
+            ```rust,ignore
+            let x = 1;
+
+
+            let y = 2;
+            ```
+
+            Third
+
+            ```rust,ignore
+            let z = 3;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn include_tests() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Text
+            }
+
+            fn helper() {}
+
+            // Check the helper.
+            #[test]
+            fn check_helper() {
+                helper();
+            }
+        "},
+        indoc! {"
+            Text
+
+            <details>
+            <summary>Tests</summary>
+
+            ```rust,ignore
+            #[test]
+            fn check_helper() {
+                helper();
+            }
+            ```
+
+            </details>
+        "},
+        &RenderOptions {
+            include_tests: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn tail_expression() {
+    check(
+        "fn body() { let x = 1; x + 1 }\n",
+        "```rust,ignore\nlet x = 1; x + 1\n```\n",
+    )
+}
+
+#[test]
+fn fence_in_comment() {
+    check(
+        indoc! {"
+            fn body() {
+                // Example:
+                //
+                // ```rust
+                // let y = 2;
+                // ```
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            Example:
+
+            ```rust
+            let y = 2;
+            ```
+
+            ````rust,ignore
+            let x = 1;
+            ````
+        "},
+    )
+}
+
+#[test]
+fn include_in_comment() {
+    check(
+        indoc! {"
+            fn body() {
+                // {{#include foo.rs}}
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            {{#include foo.rs}}
+
+            ```rust,ignore
+            let x = 1;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn anchors() {
+    check(
+        indoc! {r#"
+            fn body() {
+                // <a id="raw"></a>
+                // Text
+                let x = 1;
+                // mdbook-rust:anchor setup
+                let y = 2;
+            }
+        "#},
+        indoc! {r#"
+            <a id="raw"></a>
+            Text
+
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            <a id="setup"></a>
+
+            ```rust,ignore
+            let y = 2;
+            ```
+        "#},
+    )
+}
+
+const CFG_SOURCE: &str = indoc! {r#"
+    fn body() {
+        #[cfg(unix)]
+        let x = 1;
+        #[cfg(not(unix))]
+        let x = 2;
+        #[cfg(all(unix, feature = "extra"))]
+        show(x);
+    }
+"#};
+
+#[test]
+fn cfg_matched() {
+    check_with(
+        CFG_SOURCE,
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            show(x);
+            ```
+        "},
+        &RenderOptions {
+            cfg: Some(vec!["unix".to_string(), r#"feature = "extra""#.to_string()]),
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn cfg_unmatched() {
+    check_with(
+        CFG_SOURCE,
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            ```
+        "},
+        &RenderOptions {
+            cfg: Some(vec!["unix".to_string()]),
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn trailing_comment() {
+    check(
+        indoc! {"
+            fn body() {
+                // Text
+                let x = 1; // Inline note
+                let y = 2;
+            }
+        "},
+        indoc! {"
+            Text
+
+            ```rust,ignore
+            let x = 1; // Inline note
+            let y = 2;
+            ```
+        "},
+    )
+}
+
+const DOC_COMMENT_SOURCE: &str = indoc! {"
+    fn body() {
+        let x = 1;
+        /// Doc comment
+        let y = 2;
+    }
+"};
+
+#[test]
+fn doc_comments_as_code() {
+    check(
+        DOC_COMMENT_SOURCE,
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            /// Doc comment
+            let y = 2;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn consecutive_doc_comments() {
+    check(
+        indoc! {"
+            fn body() {
+                /// One
+                /// Two
+                /// Three
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            /// One
+            /// Two
+            /// Three
+            let x = 1;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn doc_comments_as_markdown() {
+    check_with(
+        DOC_COMMENT_SOURCE,
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            Doc comment
+
+            ```rust,ignore
+            let y = 2;
+            ```
+        "},
+        &RenderOptions {
+            doc_comments: DocComments::Markdown,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn intra_doc_links() {
+    check_with(
+        indoc! {"
+            fn body() {
+                /// Use [`Vec::new`] or [`vec!`], rather than [`struct@Vec`].
+                /// A [link][`Vec`] and [`code`](https://example.com) are left alone.
+                let v = Vec::new();
+            }
+        "},
+        indoc! {"
+            Use `Vec::new` or `vec!`, rather than `Vec`.
+            A [link][`Vec`] and [`code`](https://example.com) are left alone.
+
+            ```rust,ignore
+            let v = Vec::new();
+            ```
+        "},
+        &RenderOptions {
+            doc_comments: DocComments::Markdown,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn lang_directive() {
+    check(
+        indoc! {r##"
+            fn body() {
+                // mdbook-rust:lang sql
+                let query = r#"
+                    SELECT *
+                    FROM users
+                "#;
+                // mdbook-rust:lang rust
+                run(query);
+            }
+        "##},
+        indoc! {"
+            ```sql
+            SELECT *
+            FROM users
+            ```
+
+            ```rust,ignore
+            run(query);
+            ```
+        "},
+    )
+}
+
+#[test]
+fn inline_modules() {
+    check(
+        indoc! {"
+            fn body() {
+                // Introduction
+            }
+
+            pub mod details {
+                fn body() {
+                    // Details
+                    let x = 1;
+                }
+            }
+
+            mod private {
+                fn body() {
+                    // Private
+                }
+            }
+        "},
+        indoc! {"
+            Introduction
+
+            ## details
+
+            Details
+
+            ```rust,ignore
+            let x = 1;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn inline_module_heading_offset() {
+    // Headings are moved down by the offset, up to level 6.
+    check_with(
+        indoc! {"
+            pub mod details {
+                fn body() {
+                    // Details
+                }
+
+                pub mod more {
+                    fn body() {
+                        // More
+                    }
+                }
+            }
+        "},
+        indoc! {"
+            ###### details
+
+            Details
+
+            ###### more
+
+            More
+        "},
+        &RenderOptions {
+            heading_offset: 4,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn code_block_attribute() {
+    check(
+        indoc! {r#"
+            fn body() {
+                let x = 1;
+                // mdbook-rust:should_panic
+                panic!("x is {x}");
+                // Text
+                let y = 2;
+            }
+        "#},
+        indoc! {r#"
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            ```rust,should_panic
+            panic!("x is {x}");
+            ```
+
+            Text
+
+            ```rust,ignore
+            let y = 2;
+            ```
+        "#},
+    )
+}
+
+#[test]
+fn combined_options() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Ignored
+            }
+
+            fn chapter() {
+                /// Doc comment
+                let x = 1;
+            }
+        "},
+        indoc! {"
+            Doc comment
+
+            ```rust,no_run
+            let x = 1;
+            ```
+        "},
+        &RenderOptions {
+            entry_function: "chapter".to_string(),
+            code_attributes: "rust,no_run".to_string(),
+            doc_comments: DocComments::Markdown,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn editable_with_tests() {
+    check_with(
+        indoc! {"
+            fn body() {
+                let x = 1;
+            }
+
+            #[test]
+            fn check() {}
+        "},
+        indoc! {"
+            ```rust,editable
+            let x = 1;
+            ```
+
+            <details>
+            <summary>Tests</summary>
+
+            ```rust,editable
+            #[test]
+            fn check() {}
+            ```
+
+            </details>
+        "},
+        &RenderOptions {
+            editable: true,
+            include_tests: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn multi_line_string() {
+    check(
+        indoc! {r##"
+            fn body() {
+                let s = r#"
+                    indented string
+                "#;
+            }
+        "##},
+        indoc! {r##"
+            ```rust,ignore
+            let s = r#"
+                    indented string
+                "#;
+            ```
+        "##},
+    )
+}
+
+const CODE_DISPLAY_SOURCE: &str = indoc! {"
+    fn body() {
+        let x = 1;
+        // # Title
+        let y = 2;
+        // Text
+    }
+"};
+
+#[test]
+fn hide_code() {
+    check_with(
+        CODE_DISPLAY_SOURCE,
+        indoc! {"
+            # Title
+
+            Text
+        "},
+        &RenderOptions {
+            code: CodeDisplay::Hide,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn collapse_code() {
+    check_with(
+        CODE_DISPLAY_SOURCE,
+        indoc! {"
+            <details>
+            <summary>Code</summary>
+
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            </details>
+
+            # Title
+
+            <details>
+            <summary>Code</summary>
+
+            ```rust,ignore
+            let y = 2;
+            ```
+
+            </details>
+
+            Text
+        "},
+        &RenderOptions {
+            code: CodeDisplay::Collapse,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn structured_code_first() {
+    let chapter = write_module_structured("fn body() {\n    let x = 1;\n}\n")
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(chapter.markdown, "```rust,ignore\nlet x = 1;\n```\n");
+    assert_eq!(
+        chapter.code_blocks,
+        vec![CodeBlock {
+            code: "let x = 1;".to_string(),
+            source_range: 16..26,
+        }]
+    );
+}
+
+#[test]
+fn nested_block_with_blank_line() {
+    // The blank line has less indentation than the code, so shouldn't affect the
+    // dedent.
+    check(
+        "fn body() {\n    if true {\n        let x = 1;\n  \n        let y = 2;\n    }\n}\n",
+        indoc! {"
+            ```rust,ignore
+            if true {
+                let x = 1;
+
+                let y = 2;
+            }
+            ```
+        "},
+    )
+}
+
+#[test]
+fn entry_function_signatures() {
+    let body = indoc! {"
+        {
+            // Text
+            let x = 1;
+        }
+    "};
+    let plain = write_module(&format!("fn body() {body}")).unwrap();
+
+    for signature in [
+        "async fn body()",
+        "fn body<T: Display>() where T: Clone",
+        "pub(crate) unsafe fn body() -> impl Display",
+    ] {
+        assert_eq!(
+            write_module(&format!("{signature} {body}")).unwrap(),
+            plain,
+            "{signature}"
+        );
+    }
+
+    assert_eq!(
+        plain.unwrap(),
+        indoc! {"
+            Text
+
+            ```rust,ignore
+            let x = 1;
+            ```
+        "}
+    );
+}
+
+#[test]
+fn line_numbers() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Text
+                let x = 1;
+                let y = 2;
+                // More text
+                let z = 3;
+            }
+        "},
+        indoc! {"
+            Text
+
+            <!-- Source lines 3-4 -->
+            ```rust,ignore
+            let x = 1;
+            let y = 2;
+            ```
+
+            More text
+
+            <!-- Source line 6 -->
+            ```rust,ignore
+            let z = 3;
+            ```
+        "},
+        &RenderOptions {
+            line_numbers: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn entry_function_visibility() {
+    let source = indoc! {"
+        fn body() {
+            // Text
+        }
+    "};
+
+    assert_eq!(
+        write_module(&format!("pub {source}")).unwrap(),
+        write_module(source).unwrap()
+    );
+}
+
+#[test]
+fn markdown_escapes() {
+    // Markdown's backslash escapes are passed through, so they work as usual.
+    let chapter = write_module_structured(indoc! {r"
+        fn body() {
+            //\# Not a heading
+            //
+            // \```
+            // Not a code block
+            // \```
+        }
+    "})
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(
+        chapter.markdown,
+        indoc! {r"
+            \# Not a heading
+
+            \```
+            Not a code block
+            \```
+        "}
+    );
+    assert!(chapter.headings.is_empty());
+    assert!(chapter.code_blocks.is_empty());
+}
+
+#[test]
+fn comment_after_local_function() {
+    check(
+        indoc! {"
+            fn body() {
+                fn local() {}
+
+                // Wrap-up prose
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            fn local() {}
+            ```
+
+            Wrap-up prose
+        "},
+    )
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize_structured() {
+    let chapter = write_module_structured(indoc! {"
+        fn body() {
+            // # Title
+            let x = 1;
+        }
+    "})
+    .unwrap()
+    .unwrap();
+
+    let json = serde_json::to_value(&chapter).unwrap();
+
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "markdown": "# Title\n\n```rust,ignore\nlet x = 1;\n```\n",
+            "code_blocks": [
+                {
+                    "code": "let x = 1;",
+                    "source_range": { "start": 31, "end": 41 },
+                },
+            ],
+            "headings": ["Title"],
+            "warnings": [],
+        })
+    );
+    assert_eq!(
+        serde_json::from_value::<RenderedChapter>(json).unwrap(),
+        chapter
+    );
+}
+
+#[test]
+fn heading_offset() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // # A
+                //
+                // ## B
+                //
+                // ###### C
+                //
+                // ```text
+                // # Not a heading
+                // ```
+                //
+                // #Not a heading
+            }
+        "},
+        indoc! {"
+            ## A
+
+            ### B
+
+            ###### C
+
+            ```text
+            # Not a heading
+            ```
+
+            #Not a heading
+        "},
+        &RenderOptions {
+            heading_offset: 1,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn auto_ignore() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Standalone
+                let x = 1;
+                // Uses the parent module
+                let y = super::helper(x);
+            }
+        "},
+        indoc! {"
+            Standalone
+
+            ```rust
+            let x = 1;
+            ```
+
+            Uses the parent module
+
+            ```rust,ignore
+            let y = super::helper(x);
+            ```
+        "},
+        &RenderOptions {
+            auto_ignore: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn begin_and_end() {
+    check(
+        indoc! {"
+            fn body() {
+                let setup = 1;
+                // mdbook-rust:begin
+                // Text
+                let x = setup;
+                // mdbook-rust:end
+                assert_eq!(x, 1);
+            }
+        "},
+        indoc! {"
+            Text
+
+            ```rust,ignore
+            let x = setup;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn begin_and_end_on_items() {
+    // The directives are in comments attached to the items.
+    check(
+        indoc! {"
+            fn body() {
+                let setup = 1;
+                // Setup
+                // mdbook-rust:begin
+                fn helper() -> i32 {
+                    1
+                }
+                // Text
+                // mdbook-rust:end
+                struct Unused;
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            fn helper() -> i32 {
+                1
+            }
+            ```
+
+            Text
+        "},
+    )
+}
+
+#[test]
+fn unmatched_begin() {
+    let source = indoc! {"
+        fn body() {
+            // mdbook-rust:begin
+            let x = 1;
+            if x == 1 {
+                // mdbook-rust:end
+            }
+        }
+    "};
+
+    let chapter = write_module_structured(source).unwrap().unwrap();
+    assert_eq!(
+        chapter.warnings,
+        [
+            "2: `// mdbook-rust:begin` has no matching `// mdbook-rust:end`",
+            "5: `// mdbook-rust:end` isn't used",
+        ]
+    );
+}
+
+#[test]
+fn verbatim_code() {
+    // The first statement has trailing whitespace, which is usually removed.
+    let source = concat!(
+        "fn body() {\n",
+        "    let x = match 1 {\n",
+        "        1     => \"one\",\n",
+        "        _     => \"other\",\n",
+        "    };  \n",
+        "\n",
+        "    let y = x;\n",
+        "}\n",
+    );
+    let code = |separator: &str| {
+        format!(
+            "```rust,ignore\nlet x = match 1 {{\n    1     => \"one\",\n    _     => \
+             \"other\",\n}};{separator}\n\nlet y = x;\n```\n"
+        )
+    };
+
+    check(source, &code(""));
+    check_with(
+        source,
+        &code("  "),
+        &RenderOptions {
+            verbatim_code: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn verbatim_alignment() {
+    // Aligned code and trailing comments, and string literals, are kept exactly.
+    let source = concat!(
+        "fn body() {\n",
+        "    let a = 1;     // one\n",
+        "    let bb = 22;   // two\n",
+        "    let x = match a {\n",
+        "        1     => \"one\",   // first\n",
+        "        _     => \"other\", // rest\n",
+        "    };\n",
+        "    let s = \"a\n",
+        "        b\";\n",
+        "}\n",
+    );
+
+    check_with(
+        source,
+        concat!(
+            "```rust,ignore\n",
+            "let a = 1;     // one\n",
+            "let bb = 22;   // two\n",
+            "let x = match a {\n",
+            "    1     => \"one\",   // first\n",
+            "    _     => \"other\", // rest\n",
+            "};\n",
+            "let s = \"a\n",
+            "        b\";\n",
+            "```\n",
+        ),
+        &RenderOptions {
+            verbatim_code: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn tab_width() {
+    check_with(
+        "fn body() {\n\tif true {\n\t\tlet x = \"\ta\n\tb\";\n\t}\n}\n",
+        indoc! {"
+            ```rust,ignore
+            if true {
+              let x = \"\ta
+            \tb\";
+            }
+            ```
+        "},
+        &RenderOptions {
+            tab_width: Some(2),
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn tab_width_dedent() {
+    // With a tab width of 8, a tab and 8 spaces are the same indentation.
+    check_with(
+        "fn body() {\n\tlet x = 1;\n        let y = 2;\n\tif x == y {\n\t\tx;\n\t}\n}\n",
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            let y = 2;
+            if x == y {
+                    x;
+            }
+            ```
+        "},
+        &RenderOptions {
+            tab_width: Some(8),
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn common_dedent() {
+    check_with(
+        indoc! {"
+            fn body() {
+                let x = 1;
+            'outer: loop {
+                    break 'outer;
+                }
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            'outer: loop {
+                break 'outer;
+            }
+            ```
+        "},
+        &RenderOptions {
+            dedent_strategy: DedentStrategy::Common,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn common_dedent_nested() {
+    check_with(
+        indoc! {"
+            fn body() {
+                for i in 0..3 {
+                    a();
+                    b();
+                    c();
+                }
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            for i in 0..3 {
+                a();
+                b();
+                c();
+            }
+            ```
+        "},
+        &RenderOptions {
+            dedent_strategy: DedentStrategy::Common,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn without_dedent() {
+    check_with(
+        indoc! {"
+            fn body() {
+                // Text
+                let x = 1;
+                if x == 1 {
+                    x;
+                }
+            }
+        "},
+        indoc! {"
+            Text
+
+            ```rust,ignore
+                let x = 1;
+                if x == 1 {
+                    x;
+                }
+            ```
+        "},
+        &RenderOptions {
+            dedent: false,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+/// Make sure the example book renders as documented.
+#[test]
+fn example_book() {
+    check(
+        include_str!("../../../examples/book/src/chapter1.rs"),
+        include_str!("snapshots/chapter1.md"),
+    )
+}
+
+#[test]
+fn doc_comment_with_example() {
+    check(
+        indoc! {"
+            fn body() {
+                let x = 1;
+                /// Example:
+                ///
+                /// ```rust
+                /// let y = 2;
+                /// ```
+                let z = 3;
+            }
+        "},
+        indoc! {"
+            ````rust,ignore
+            let x = 1;
+            ````
+
+            Example:
+
+            ```rust
+            let y = 2;
+            ```
+
+            ````rust,ignore
+            let z = 3;
+            ````
+        "},
+    )
+}
+
+#[test]
+fn function_context() {
+    check_with(
+        indoc! {"
+            fn body<T: Default>()
+            where
+                T: Clone,
+            {
+                // Text
+                let x = T::default();
+            }
+        "},
+        indoc! {"
+            Text
+
+            ```rust,ignore
+            # fn body<T: Default>() where T: Clone, {
+            let x = T::default();
+            # }
+            ```
+        "},
+        &RenderOptions {
+            context: CodeContext::Function,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn show_signature() {
+    let source = indoc! {"
+        fn body() -> String {
+            // Text
+            String::new()
+        }
+    "};
+    let body = indoc! {"
+        Text
+
+        ```rust,ignore
+        String::new()
+        ```
+    "};
+
+    check(source, body);
+    check_with(
+        source,
+        &format!("`fn body() -> String`\n\n{body}"),
+        &RenderOptions {
+            show_signature: true,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn comment_gutter() {
+    check(
+        indoc! {"
+            fn body() {
+                //  Two spaces
+                //
+                //  - Item
+                //    continued
+                let x = 1;
+                // One space
+            }
+        "},
+        indoc! {"
+            Two spaces
+
+            - Item
+              continued
+
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            One space
+        "},
+    )
+}
+
+#[test]
+fn fixed_comment_gutter() {
+    check_with(
+        indoc! {"
+            fn body() {
+                //  Two spaces
+                // One space
+            }
+        "},
+        indoc! {"
+             Two spaces
+            One space
+        "},
+        &RenderOptions {
+            comment_gutter: CommentGutter::Spaces(1),
+            ..RenderOptions::default()
+        },
+    )
+}
+
+#[test]
+fn fenced_block_in_comments() {
+    // Fenced blocks in comments, like Mermaid diagrams, always have blank lines
+    // around them.
+    check(
+        indoc! {"
+            fn body() {
+                let x = 1;
+                // ```mermaid
+                // graph LR
+                // A --> B
+                // ```
+                // Text
+                // ```mermaid
+                // graph TD
+                // ```
+                let y = 2;
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            ```mermaid
+            graph LR
+            A --> B
+            ```
+
+            Text
+
+            ```mermaid
+            graph TD
+            ```
+
+            ```rust,ignore
+            let y = 2;
+            ```
+        "},
+    )
+}
+
+#[test]
+fn blank_lines_around_code() {
+    // There's always exactly one blank line between code and Markdown, but blank
+    // lines within code are kept.
+    check(
+        indoc! {"
+            fn body() {
+                let x = 1;
+
+
+                // Text
+
+                let y = 2;
+
+                let z = 3;
+                // More text
+
+
+                let w = 4;
+                // Final text
+            }
+        "},
+        indoc! {"
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            Text
+
+            ```rust,ignore
+            let y = 2;
+
+            let z = 3;
+            ```
+
+            More text
+
+            ```rust,ignore
+            let w = 4;
+            ```
+
+            Final text
+        "},
+    )
+}
+
+#[test]
+fn whole_file_matches_body() {
+    let options = RenderOptions {
+        whole_file: true,
+        ..RenderOptions::default()
+    };
+    let whole_file = indoc! {"
+        // Some text
+        struct Point {
+            x: i32,
+        }
+
+        // More text
+        fn origin() -> Point {
+            Point { x: 0 }
+        }
+    "};
+    let body = indoc! {"
+        fn body() {
+            // Some text
+            struct Point {
+                x: i32,
+            }
+
+            // More text
+            fn origin() -> Point {
+                Point { x: 0 }
+            }
+        }
+    "};
+
+    assert_eq!(
+        write_module_with(whole_file, &options).unwrap(),
+        write_module(body).unwrap()
+    );
+}
+
+#[test]
+fn whole_file() {
+    check_with(
+        indoc! {"
+            //! # Title
+
+            // Some text
+            fn main() {
+                let x = 1;
+            }
+
+            #[test]
+            fn test() {}
+        "},
+        indoc! {"
+            # Title
+
+            Some text
+
+            ```rust,ignore
+            fn main() {
+                let x = 1;
+            }
+            ```
+        "},
+        &RenderOptions {
+            whole_file: true,
+            ..RenderOptions::default()
+        },
+    )
+}
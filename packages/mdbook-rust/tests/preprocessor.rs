@@ -0,0 +1,751 @@
+use std::{fs, path::Path, sync::Mutex};
+
+use indoc::indoc;
+use mdbook::{
+    book::{Book, Chapter},
+    preprocess::{Preprocessor, PreprocessorContext},
+    BookItem,
+};
+use mdbook_rust::{
+    check_version, diagnose, unknown_config_keys, write_book, write_chapter, ChapterDiagnosis,
+    ChapterSummary, CodeContext, FirstHeading, OnParseError, RenderOptions, RustPreprocessor,
+};
+use serde_json::json;
+use toml::value::Table;
+
+const SOURCE: &str = indoc! {"
+    fn body() {
+        // Text
+        let x = 1;
+    }
+"};
+
+const RENDERED: &str = indoc! {"
+    Text
+
+    ```rust,ignore
+    let x = 1;
+    ```
+"};
+
+const BROKEN: &str = indoc! {"
+    fn body() {
+        let x = ;
+    }
+"};
+
+fn chapter(path: &str, content: &str) -> Chapter {
+    Chapter::new("Chapter", content.to_string(), path, Vec::new())
+}
+
+#[test]
+fn rust_chapter() {
+    let mut chapter = chapter("chapter.rs", SOURCE);
+
+    let summary = write_chapter(&mut chapter, &RenderOptions::default()).unwrap();
+
+    assert_eq!(chapter.content, RENDERED);
+    assert_eq!(
+        summary,
+        Some(ChapterSummary {
+            path: "chapter.rs".into(),
+            code_blocks: 1,
+            headings: 0,
+        })
+    );
+}
+
+#[test]
+fn show_source_name() {
+    let mut chapter = chapter("chapter.rs", SOURCE);
+    let options = RenderOptions {
+        show_source_name: true,
+        ..RenderOptions::default()
+    };
+
+    write_chapter(&mut chapter, &options).unwrap();
+
+    assert_eq!(
+        chapter.content,
+        indoc! {"
+            *chapter.rs*
+
+            Text
+
+            ```rust,ignore
+            let x = 1;
+            ```
+        "}
+    );
+}
+
+#[test]
+fn chapter_title() {
+    let mut chapter = chapter(
+        "chapter.rs",
+        &format!("// mdbook-rust:title \"Custom Title\"\n\n{SOURCE}"),
+    );
+
+    write_chapter(&mut chapter, &RenderOptions::default()).unwrap();
+
+    assert_eq!(chapter.name, "Custom Title");
+    assert_eq!(chapter.content, RENDERED);
+}
+
+#[test]
+fn escaped_chapter_title() {
+    let source = format!(
+        r##"// mdbook-rust:title r#"A "Raw" Title"#
+// mdbook-rust:tags "a\"b"
+
+{SOURCE}"##
+    );
+    let mut chapter = chapter("chapter.rs", &source);
+    let options = RenderOptions {
+        front_matter: true,
+        ..RenderOptions::default()
+    };
+
+    write_chapter(&mut chapter, &options).unwrap();
+
+    assert_eq!(chapter.name, "A \"Raw\" Title");
+    assert!(chapter
+        .content
+        .starts_with("---\ntitle: \"A \\\"Raw\\\" Title\"\ntags: [\"a\\\"b\"]\n---\n"));
+}
+
+#[test]
+fn title_without_string() {
+    let mut chapter = chapter(
+        "chapter.rs",
+        &format!("// mdbook-rust:title Title\n{SOURCE}"),
+    );
+    let error = write_chapter(&mut chapter, &RenderOptions::default()).unwrap_err();
+
+    assert_eq!(
+        error.to_string(),
+        "chapter.rs:1: `title` should be followed by a string literal"
+    );
+}
+
+/// Metadata directives are comments, so chapters that use them still compile.
+/// The example book's crate includes this chapter as a module.
+#[test]
+fn example_chapter_metadata() {
+    let mut chapter = chapter(
+        "chapter3.rs",
+        include_str!("../../../examples/book/src/chapter3.rs"),
+    );
+
+    write_chapter(&mut chapter, &RenderOptions::default()).unwrap();
+
+    assert_eq!(chapter.name, "Chapter 3: \"Metadata\"");
+}
+
+#[test]
+fn book_chapter_title() {
+    let mut book = Book::new();
+    book.push_item(chapter(
+        "chapter.rs",
+        &format!("// mdbook-rust:title \"Custom Title\"\n\n{SOURCE}"),
+    ));
+
+    write_book(&mut book, &RenderOptions::default()).unwrap();
+
+    let mut expected = chapter("chapter.rs", RENDERED);
+    expected.name = "Custom Title".to_string();
+    assert_eq!(book.sections, vec![BookItem::Chapter(expected)]);
+}
+
+#[test]
+fn front_matter() {
+    let mut chapter = chapter(
+        "chapter.rs",
+        &format!(
+            "// mdbook-rust:title \"Custom Title\"\n// mdbook-rust:tags \"intro, basics\"\n\n{SOURCE}"
+        ),
+    );
+    let options = RenderOptions {
+        front_matter: true,
+        ..RenderOptions::default()
+    };
+
+    write_chapter(&mut chapter, &options).unwrap();
+
+    assert_eq!(
+        chapter.content,
+        format!("---\ntitle: \"Custom Title\"\ntags: [\"intro\",\"basics\"]\n---\n\n{RENDERED}")
+    );
+}
+
+#[test]
+fn default_front_matter() {
+    let mut chapter = chapter("chapter.rs", "fn body() {\n    // # Heading\n}\n");
+    let options = RenderOptions {
+        front_matter: true,
+        ..RenderOptions::default()
+    };
+
+    let summary = write_chapter(&mut chapter, &options).unwrap().unwrap();
+
+    assert_eq!(
+        chapter.content,
+        "---\ntitle: \"Chapter\"\n---\n\n# Heading\n"
+    );
+    assert_eq!(summary.headings, 1);
+}
+
+#[test]
+fn source_link() {
+    let mut chapter = chapter("part/chapter.rs", SOURCE);
+    let options = RenderOptions {
+        source_link_base: Some("https://example.com/src/".to_string()),
+        ..RenderOptions::default()
+    };
+
+    write_chapter(&mut chapter, &options).unwrap();
+
+    assert_eq!(
+        chapter.content,
+        format!(
+            "{}\n\n[View source](https://example.com/src/part/chapter.rs)\n",
+            RENDERED.trim_end()
+        )
+    );
+}
+
+#[test]
+fn title_from_first_heading() {
+    let source = "fn body() {\n    // # First Heading\n    //\n    // Text\n}\n";
+    let render = |first_heading| {
+        let mut chapter = chapter("chapter.rs", source);
+        let options = RenderOptions {
+            first_heading,
+            ..RenderOptions::default()
+        };
+
+        write_chapter(&mut chapter, &options).unwrap();
+        (chapter.name, chapter.content)
+    };
+
+    assert_eq!(
+        render(FirstHeading::Keep),
+        (
+            "Chapter".to_string(),
+            "# First Heading\n\nText\n".to_string()
+        )
+    );
+    assert_eq!(
+        render(FirstHeading::Use),
+        ("First Heading".to_string(), "Text\n".to_string())
+    );
+    assert_eq!(
+        render(FirstHeading::Strip),
+        ("Chapter".to_string(), "Text\n".to_string())
+    );
+}
+
+#[test]
+fn preprocessor_title_from_first_heading() {
+    let ctx: PreprocessorContext = serde_json::from_value(json!({
+        "root": "/book",
+        "config": {
+            "preprocessor": {
+                "rust": {
+                    "title-from-first-heading": "use",
+                },
+            },
+        },
+        "renderer": "html",
+        "mdbook_version": mdbook::MDBOOK_VERSION,
+    }))
+    .unwrap();
+    let mut book = Book::new();
+    book.push_item(chapter(
+        "chapter.rs",
+        "fn body() {\n    // # First Heading\n    //\n    // Text\n}\n",
+    ));
+
+    let book = RustPreprocessor.run(&ctx, book).unwrap();
+
+    let mut expected = chapter("chapter.rs", "Text\n");
+    expected.name = "First Heading".to_string();
+    assert_eq!(book.sections, vec![BookItem::Chapter(expected)]);
+}
+
+#[test]
+fn draft_chapter() {
+    let mut chapter = chapter("chapter.rs", &format!("// mdbook-rust:draft\n{SOURCE}"));
+
+    write_chapter(&mut chapter, &RenderOptions::default()).unwrap();
+
+    assert_eq!(chapter.name, "Chapter");
+    assert_eq!(chapter.content, "");
+}
+
+#[test]
+fn markdown_chapter() {
+    let mut chapter = chapter("chapter.md", SOURCE);
+
+    let summary = write_chapter(&mut chapter, &RenderOptions::default()).unwrap();
+
+    assert_eq!(chapter.content, SOURCE);
+    assert_eq!(summary, None);
+}
+
+#[test]
+fn custom_extensions() {
+    let options = RenderOptions {
+        extensions: vec!["rust".to_string(), "rs.md".to_string()],
+        ..RenderOptions::default()
+    };
+
+    for path in ["chapter.rust", "chapter.rs.md"] {
+        let mut chapter = chapter(path, SOURCE);
+        write_chapter(&mut chapter, &options).unwrap();
+        assert_eq!(chapter.content, RENDERED, "{path}");
+    }
+
+    let mut unlisted = chapter("chapter.rs", SOURCE);
+    write_chapter(&mut unlisted, &options).unwrap();
+    assert_eq!(unlisted.content, SOURCE);
+}
+
+#[test]
+fn rust_chapter_without_body() {
+    let source = "fn not_a_body() {}\n";
+    let mut chapter = chapter("chapter.rs", source);
+
+    write_chapter(&mut chapter, &RenderOptions::default()).unwrap();
+
+    assert_eq!(chapter.content, source);
+}
+
+#[test]
+fn fail_on_parse_error() {
+    let mut chapter = chapter("broken.rs", BROKEN);
+
+    let error = write_chapter(&mut chapter, &RenderOptions::default()).unwrap_err();
+
+    assert!(error.to_string().starts_with("broken.rs:2: "));
+}
+
+#[test]
+fn skip_on_parse_error() {
+    let mut chapter = chapter("broken.rs", BROKEN);
+    let options = RenderOptions {
+        on_parse_error: OnParseError::Skip,
+        ..RenderOptions::default()
+    };
+
+    write_chapter(&mut chapter, &options).unwrap();
+
+    assert!(chapter.content.starts_with("> **Warning:**"));
+    assert!(chapter.content.ends_with(BROKEN));
+}
+
+#[test]
+fn skip_on_parse_error_with_denied_warning() {
+    let mut chapter = chapter("declaration.rs", "fn body();\n");
+    let options = RenderOptions {
+        on_parse_error: OnParseError::Skip,
+        deny_warnings: true,
+        ..RenderOptions::default()
+    };
+
+    // Only parse errors are skipped.
+    let error = write_chapter(&mut chapter, &options).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "declaration.rs:1: `body` has no body, so there's nothing to render"
+    );
+}
+
+#[test]
+fn many_chapters() {
+    let mut book = Book::new();
+
+    for index in 0..100 {
+        let mut rust_chapter = chapter(&format!("chapter{index}.rs"), SOURCE);
+        rust_chapter.sub_items.push(BookItem::Chapter(chapter(
+            &format!("section{index}.md"),
+            SOURCE,
+        )));
+        book.push_item(rust_chapter);
+    }
+
+    write_book(&mut book, &RenderOptions::default()).unwrap();
+
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            let path = chapter.path.as_ref().unwrap();
+            let expected = if path.extension() == Some("rs".as_ref()) {
+                RENDERED
+            } else {
+                SOURCE
+            };
+
+            assert_eq!(chapter.content, expected, "{}", path.display());
+        }
+    }
+}
+
+#[test]
+fn part_title() {
+    let mut book = Book::new();
+    book.push_item(chapter("intro.md", SOURCE));
+    book.push_item(chapter(
+        "advanced.rs",
+        &format!("// mdbook-rust:part \"Advanced \\\"Topics\\\"\"\n{SOURCE}"),
+    ));
+
+    write_book(&mut book, &RenderOptions::default()).unwrap();
+
+    assert_eq!(
+        book.sections,
+        vec![
+            BookItem::Chapter(chapter("intro.md", SOURCE)),
+            BookItem::PartTitle("Advanced \"Topics\"".to_string()),
+            BookItem::Chapter(chapter("advanced.rs", RENDERED)),
+        ]
+    );
+}
+
+#[test]
+fn cached_chapter() {
+    let cache_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cached_chapter");
+    let _ = fs::remove_dir_all(&cache_dir);
+    let options = RenderOptions {
+        cache_dir: Some(cache_dir.clone()),
+        ..RenderOptions::default()
+    };
+
+    let mut first = chapter("chapter.rs", SOURCE);
+    write_chapter(&mut first, &options).unwrap();
+    assert_eq!(first.content, RENDERED);
+
+    // Overwrite the cache entry, so we can tell when it's used. The metadata is
+    // cached too, so the chapter isn't parsed.
+    let cache_entries = fs::read_dir(&cache_dir).unwrap().collect::<Vec<_>>();
+    assert_eq!(cache_entries.len(), 1);
+    let cached = json!({
+        "title": "Cached Title",
+        "draft": false,
+        "tags": [],
+        "part_title": null,
+        "warnings": [],
+        "markdown": "Cached\n",
+    });
+    fs::write(
+        cache_entries[0].as_ref().unwrap().path(),
+        cached.to_string(),
+    )
+    .unwrap();
+
+    let mut second = chapter("chapter.rs", SOURCE);
+    write_chapter(&mut second, &options).unwrap();
+    assert_eq!(second.name, "Cached Title");
+    assert_eq!(second.content, "Cached\n");
+
+    let changed_source = SOURCE.replace("Text", "Changed");
+    let mut changed = chapter("chapter.rs", &changed_source);
+    write_chapter(&mut changed, &options).unwrap();
+    assert_eq!(changed.content, RENDERED.replace("Text", "Changed"));
+}
+
+#[test]
+fn include_chapter() {
+    let source_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("include_chapter");
+    fs::create_dir_all(&source_dir).unwrap();
+    fs::write(source_dir.join("child.rs"), SOURCE).unwrap();
+    let options = RenderOptions {
+        source_dir: Some(source_dir),
+        ..RenderOptions::default()
+    };
+    let mut parent = chapter(
+        "parent.rs",
+        indoc! {"
+            fn body() {
+                // Parent text
+                //
+                // mdbook-rust:include child.rs
+                let y = 2;
+            }
+        "},
+    );
+
+    write_chapter(&mut parent, &options).unwrap();
+
+    assert_eq!(
+        parent.content,
+        indoc! {"
+            Parent text
+
+            Text
+
+            ```rust,ignore
+            let x = 1;
+            ```
+
+            ```rust,ignore
+            let y = 2;
+            ```
+        "}
+    );
+}
+
+#[test]
+fn include_cycle() {
+    let source_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("include_cycle");
+    fs::create_dir_all(&source_dir).unwrap();
+    let first = "fn body() {\n    // mdbook-rust:include second.rs\n}\n";
+    fs::write(source_dir.join("first.rs"), first).unwrap();
+    fs::write(
+        source_dir.join("second.rs"),
+        "fn body() {\n    // mdbook-rust:include first.rs\n}\n",
+    )
+    .unwrap();
+    let options = RenderOptions {
+        source_dir: Some(source_dir),
+        ..RenderOptions::default()
+    };
+    let mut chapter = chapter("first.rs", first);
+
+    let error = write_chapter(&mut chapter, &options).unwrap_err();
+
+    assert!(error.to_string().starts_with("Include cycle: "));
+}
+
+#[test]
+fn diagnose_book() {
+    let mut book = Book::new();
+    book.push_item(chapter("intro.md", SOURCE));
+    book.push_item(chapter("chapter.rs", SOURCE));
+    book.push_item(chapter("no_body.rs", "fn other() {}\n"));
+    book.push_item(chapter("broken.rs", BROKEN));
+
+    let diagnoses = diagnose(&book, &RenderOptions::default());
+
+    assert_eq!(diagnoses.len(), 3);
+    assert_eq!(
+        diagnoses[0],
+        ChapterDiagnosis {
+            path: "chapter.rs".into(),
+            has_entry_function: true,
+            code_blocks: 1,
+            prose_sections: 1,
+            warnings: Vec::new(),
+            error: None,
+        }
+    );
+    assert_eq!(diagnoses[1].to_string(), "no_body.rs: No entry function");
+    assert!(diagnoses[2]
+        .error
+        .as_ref()
+        .unwrap()
+        .starts_with("broken.rs:2: "));
+}
+
+#[test]
+fn entry_function_without_body() {
+    let source = "fn body();\n";
+    let mut book = Book::new();
+    book.push_item(chapter("declaration.rs", source));
+
+    let diagnoses = diagnose(&book, &RenderOptions::default());
+
+    assert_eq!(
+        diagnoses[0].to_string(),
+        indoc! {"
+            declaration.rs: No entry function
+            Warning: declaration.rs:1: `body` has no body, so there's nothing to render"
+        }
+    );
+
+    let options = RenderOptions {
+        deny_warnings: true,
+        ..RenderOptions::default()
+    };
+    let error = write_chapter(&mut chapter("declaration.rs", source), &options).unwrap_err();
+
+    assert_eq!(
+        error.to_string(),
+        "declaration.rs:1: `body` has no body, so there's nothing to render"
+    );
+}
+
+#[test]
+fn preprocessor() {
+    let ctx: PreprocessorContext = serde_json::from_value(json!({
+        "root": "/book",
+        "config": {
+            "preprocessor": {
+                "rust": {
+                    "code-attributes": "rust,no_run",
+                },
+            },
+        },
+        "renderer": "html",
+        "mdbook_version": mdbook::MDBOOK_VERSION,
+    }))
+    .unwrap();
+    let mut book = Book::new();
+    book.push_item(chapter("chapter.rs", SOURCE));
+
+    let book = RustPreprocessor.run(&ctx, book).unwrap();
+
+    assert_eq!(
+        book.sections,
+        vec![BookItem::Chapter(chapter(
+            "chapter.rs",
+            &RENDERED.replace("rust,ignore", "rust,no_run")
+        ))]
+    );
+}
+
+#[test]
+fn manifest() {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join("manifest");
+    fs::create_dir_all(&root).unwrap();
+    let ctx: PreprocessorContext = serde_json::from_value(json!({
+        "root": root,
+        "config": {
+            "preprocessor": {
+                "rust": {
+                    "manifest": "manifest.json",
+                },
+            },
+        },
+        "renderer": "html",
+        "mdbook_version": mdbook::MDBOOK_VERSION,
+    }))
+    .unwrap();
+    let mut book = Book::new();
+    book.push_item(chapter("intro.md", SOURCE));
+    book.push_item(chapter(
+        "chapter.rs",
+        indoc! {"
+            fn body() {
+                // # Title
+                let x = 1;
+                // ## Section
+                let y = 2;
+            }
+        "},
+    ));
+
+    RustPreprocessor.run(&ctx, book).unwrap();
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(root.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(
+        manifest,
+        json!({
+            "chapters": [
+                {
+                    "path": "chapter.rs",
+                    "code_blocks": 2,
+                    "headings": 2,
+                },
+            ],
+        })
+    );
+}
+
+/// Collects warnings logged by the preprocessor, so tests can check for them.
+struct TestLogger;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+impl log::Log for TestLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[test]
+fn version_mismatch_warning() {
+    // This is the only test that sets a logger.
+    log::set_logger(&TestLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let ctx: PreprocessorContext = serde_json::from_value(json!({
+        "root": "/book",
+        "config": {
+            "preprocessor": {
+                "rust": {},
+            },
+        },
+        "renderer": "html",
+        "mdbook_version": "0.1.0",
+    }))
+    .unwrap();
+    RustPreprocessor.run(&ctx, Book::new()).unwrap();
+
+    assert!(WARNINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|warning| warning.starts_with("MDBook version (0.1.0) doesn't match")));
+}
+
+#[test]
+fn matching_version() {
+    assert_eq!(check_version(mdbook::MDBOOK_VERSION, true).unwrap(), None);
+}
+
+#[test]
+fn mismatched_version() {
+    let warning = check_version("0.1.0", false).unwrap().unwrap();
+
+    assert!(warning.starts_with("MDBook version (0.1.0) doesn't match"));
+}
+
+#[test]
+fn strict_mismatched_version() {
+    assert!(check_version("0.1.0", true).is_err());
+}
+
+fn config(toml: &str) -> Table {
+    toml::from_str(toml).unwrap()
+}
+
+#[test]
+fn options_from_config() {
+    let options = RenderOptions::from_config(Some(&config(indoc! {r#"
+        editable = true
+        context = "function"
+        tab-width = 2
+        cache-dir = "cache"
+    "#})))
+    .unwrap();
+
+    assert!(options.editable);
+    assert_eq!(options.context, CodeContext::Function);
+    assert_eq!(options.tab_width, Some(2));
+    assert_eq!(options.cache_dir, Some("cache".into()));
+}
+
+#[test]
+fn config_type_error() {
+    let error = RenderOptions::from_config(Some(&config(r#"editable = "yes""#))).unwrap_err();
+    assert_eq!(error.to_string(), "`editable` should be a boolean");
+}
+
+#[test]
+fn unknown_config_key() {
+    let config = config(indoc! {r#"
+        command = "mdbook-rust"
+        editabel = true
+        wrap-main = true
+    "#});
+    assert_eq!(unknown_config_keys(&config), ["editabel"]);
+}